@@ -13,6 +13,10 @@ TDKlambda_registerRecordDeviceDriver pdbbase
 
 ## Load record instances
 dbLoadTemplate("db/records.substitution")
+dbLoadRecords("db/bus.db", "BUS=TDK0:")
+
+## Backend configuration
+epicsEnvSet("TDK_LAMBDA_CONFIG", "${TOP}/iocBoot/${IOC}/config.toml")
 
 cd "${TOP}/iocBoot/${IOC}"
 iocInit
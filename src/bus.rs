@@ -0,0 +1,366 @@
+use ferrite::{variable::*, Context};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{join, runtime, sync::Mutex as AsyncMutex, task::JoinHandle, time::interval};
+
+use crate::{
+    config::{self, MAX_ADDR},
+    device::{self, take, Device},
+    serial::{Addr, Broadcaster, Control, Stats},
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Device address {0} is out of range 0..={}", MAX_ADDR)]
+    OutOfRange(i64),
+    #[error("Device {0} is already served")]
+    Present(Addr),
+    #[error("Device {0} is not served")]
+    Absent(Addr),
+    #[error("No records with prefix '{0}'")]
+    NoRecords(String),
+}
+
+/// Period of counters update.
+const PERIOD: Duration = Duration::from_secs(1);
+
+/// Record showing value of counter.
+struct Counter {
+    var: Variable<i32, false, true, true>,
+    value: Option<u64>,
+}
+
+impl Counter {
+    fn new(epics: &mut Context, name: &str) -> Self {
+        Self {
+            var: take(epics, name),
+            value: None,
+        }
+    }
+
+    /// Write counter value to record if it has changed.
+    async fn update(&mut self, counter: &AtomicU64) {
+        self.write(counter.load(Ordering::Relaxed)).await;
+    }
+
+    /// Write value accumulated since previous reading and reset it.
+    async fn take(&mut self, counter: &AtomicU64) {
+        self.write(counter.swap(0, Ordering::Relaxed)).await;
+    }
+
+    async fn write(&mut self, value: u64) {
+        if self.value != Some(value) {
+            self.value.replace(value);
+            let value = i32::try_from(value).unwrap_or(i32::MAX);
+            self.var.request().await.write(value).await;
+        }
+    }
+}
+
+struct Counters {
+    requests: Counter,
+    adr_switches: Counter,
+    timeouts: Counter,
+    resyncs: Counter,
+    mismatches: Counter,
+    checksum_errors: Counter,
+    collisions: Counter,
+    unknown_srqs: Counter,
+    imm_pending: Counter,
+    que_pending: Counter,
+    imm_wait: Counter,
+    que_wait: Counter,
+}
+
+/// Record which value is broadcast to all devices with given command.
+struct Setting<T> {
+    cmd: &'static str,
+    var: Variable<T, true, true, false>,
+}
+
+impl<T: Copy + Display> Setting<T> {
+    fn new(cmd: &'static str, epics: &mut Context, name: &str) -> Self {
+        Self {
+            cmd,
+            var: take(epics, name),
+        }
+    }
+
+    async fn run(&mut self, bcast: &Broadcaster) {
+        loop {
+            let var = self.var.acquire().await;
+            let cmd = format!("{} {}", self.cmd, *var);
+            if bcast.broadcast(cmd).await {
+                var.accept().await;
+            } else {
+                var.reject("Command wasn't sent").await;
+            }
+        }
+    }
+}
+
+/// Record which broadcasts command without arguments when non-zero value is written.
+struct Trigger {
+    cmd: &'static str,
+    var: Variable<u16, true, true, false>,
+}
+
+impl Trigger {
+    fn new(cmd: &'static str, epics: &mut Context, name: &str) -> Self {
+        Self {
+            cmd,
+            var: take(epics, name),
+        }
+    }
+
+    async fn run(&mut self, bcast: &Broadcaster) {
+        loop {
+            let var = self.var.acquire().await;
+            if *var == 0 || bcast.broadcast(String::from(self.cmd)).await {
+                var.accept().await;
+            } else {
+                var.reject("Command wasn't sent").await;
+            }
+        }
+    }
+}
+
+struct Commands {
+    out_ena: Setting<u16>,
+    volt_set: Setting<f64>,
+    curr_set: Setting<f64>,
+    reset: Trigger,
+    save: Trigger,
+    recall: Trigger,
+}
+
+/// Devices of the bus, they can be added and removed while bus is running.
+pub struct Devices {
+    config: config::Bus,
+    control: Control,
+    /// Records which aren't used yet.
+    epics: Arc<Mutex<Context>>,
+    running: HashMap<Addr, JoinHandle<Device>>,
+    /// Removed devices keeping their records.
+    parked: HashMap<Addr, Device>,
+}
+
+impl Devices {
+    pub fn new(config: config::Bus, control: Control, epics: Arc<Mutex<Context>>) -> Self {
+        Self {
+            config,
+            control,
+            epics,
+            running: HashMap::new(),
+            parked: HashMap::new(),
+        }
+    }
+
+    /// Start serving device.
+    pub fn add(&mut self, dev: &config::Device) -> Result<(), Error> {
+        if dev.addr > MAX_ADDR {
+            return Err(Error::OutOfRange(dev.addr.into()));
+        }
+        let handle = self
+            .control
+            .add_client(dev.addr)
+            .ok_or(Error::Present(dev.addr))?;
+        let device = match self.parked.remove(&dev.addr) {
+            Some(mut device) => {
+                device.attach(handle);
+                device
+            }
+            None => {
+                let prefix = self.config.device_prefix(dev);
+                let mut epics = self.epics.lock().unwrap();
+                if !device::has_records(&epics, &prefix) {
+                    self.control.remove_client(dev.addr);
+                    return Err(Error::NoRecords(prefix));
+                }
+                let scan = dev.scan.periods();
+                let model = self.config.protocol.model();
+                Device::new(
+                    &prefix,
+                    model,
+                    dev.dialect.fixed(),
+                    scan,
+                    &mut epics,
+                    handle,
+                )
+            }
+        };
+        log::info!("Bus '{}': Device {} added", self.config.name, dev.addr);
+        let task = runtime::Handle::current().spawn(device.run());
+        self.running.insert(dev.addr, task);
+        Ok(())
+    }
+
+    /// Stop serving device, its records are kept to add it again later.
+    pub async fn remove(&mut self, addr: Addr) -> Result<(), Error> {
+        let task = self.running.remove(&addr).ok_or(Error::Absent(addr))?;
+        self.control.remove_client(addr);
+        self.parked.insert(addr, task.await.unwrap());
+        log::info!("Bus '{}': Device {} removed", self.config.name, addr);
+        Ok(())
+    }
+}
+
+/// Address written to record.
+fn parse_addr(value: i32) -> Result<Addr, Error> {
+    Addr::try_from(value)
+        .ok()
+        .filter(|addr| *addr <= MAX_ADDR)
+        .ok_or(Error::OutOfRange(value.into()))
+}
+
+/// Check that EPICS records for bus with given prefix exist.
+pub fn has_records(epics: &Context, prefix: &str) -> bool {
+    epics.registry.contains_key(&format!("{}requests", prefix))
+}
+
+/// Bus-level records.
+pub struct Bus {
+    stats: Arc<Stats>,
+    counters: Counters,
+    bcast: Broadcaster,
+    commands: Commands,
+    devices: Devices,
+    dev_add: Variable<i32, true, true, false>,
+    dev_remove: Variable<i32, true, true, false>,
+}
+
+impl Bus {
+    pub fn new(
+        prefix: &str,
+        epics: &mut Context,
+        stats: Arc<Stats>,
+        bcast: Broadcaster,
+        devices: Devices,
+    ) -> Self {
+        let name = |name: &str| format!("{}{}", prefix, name);
+        Self {
+            stats,
+            counters: Counters {
+                requests: Counter::new(epics, &name("requests")),
+                adr_switches: Counter::new(epics, &name("adr_switches")),
+                timeouts: Counter::new(epics, &name("timeouts")),
+                resyncs: Counter::new(epics, &name("resyncs")),
+                mismatches: Counter::new(epics, &name("mismatches")),
+                checksum_errors: Counter::new(epics, &name("checksum_errors")),
+                collisions: Counter::new(epics, &name("collisions")),
+                unknown_srqs: Counter::new(epics, &name("unknown_srqs")),
+                imm_pending: Counter::new(epics, &name("imm_pending")),
+                que_pending: Counter::new(epics, &name("que_pending")),
+                imm_wait: Counter::new(epics, &name("imm_wait")),
+                que_wait: Counter::new(epics, &name("que_wait")),
+            },
+            bcast,
+            commands: Commands {
+                out_ena: Setting::new("GOUT", epics, &name("out_ena")),
+                volt_set: Setting::new("GPV", epics, &name("volt_set")),
+                curr_set: Setting::new("GPC", epics, &name("curr_set")),
+                reset: Trigger::new("GRST", epics, &name("reset")),
+                save: Trigger::new("GSAV", epics, &name("save")),
+                recall: Trigger::new("GRCL", epics, &name("recall")),
+            },
+            devices,
+            dev_add: take(epics, &name("dev_add")),
+            dev_remove: take(epics, &name("dev_remove")),
+        }
+    }
+
+    /// Add and remove devices by addresses written to records.
+    ///
+    /// Each record is served by its own loop, so that no acquired value is dropped.
+    async fn manage(
+        devices: &mut Devices,
+        dev_add: &mut Variable<i32, true, true, false>,
+        dev_remove: &mut Variable<i32, true, true, false>,
+    ) {
+        let devices = AsyncMutex::new(devices);
+        join!(
+            async {
+                loop {
+                    let var = dev_add.acquire().await;
+                    let mut devices = devices.lock().await;
+                    let res = match parse_addr(*var) {
+                        Ok(addr) => {
+                            let dev = devices.config.device(addr);
+                            devices.add(&dev)
+                        }
+                        Err(err) => Err(err),
+                    };
+                    match res {
+                        Ok(()) => var.accept().await,
+                        Err(err) => {
+                            log::error!("Bus '{}': {}", devices.config.name, err);
+                            var.reject(&err.to_string()).await;
+                        }
+                    }
+                }
+            },
+            async {
+                loop {
+                    let var = dev_remove.acquire().await;
+                    let mut devices = devices.lock().await;
+                    let res = match parse_addr(*var) {
+                        Ok(addr) => devices.remove(addr).await,
+                        Err(err) => Err(err),
+                    };
+                    match res {
+                        Ok(()) => var.accept().await,
+                        Err(err) => {
+                            log::error!("Bus '{}': {}", devices.config.name, err);
+                            var.reject(&err.to_string()).await;
+                        }
+                    }
+                }
+            },
+        );
+    }
+
+    async fn update(stats: &Stats, counters: &mut Counters) {
+        let mut timer = interval(PERIOD);
+        loop {
+            timer.tick().await;
+            counters.requests.update(&stats.requests).await;
+            counters.adr_switches.update(&stats.adr_switches).await;
+            counters.timeouts.update(&stats.timeouts).await;
+            counters.resyncs.update(&stats.resyncs).await;
+            counters.mismatches.update(&stats.mismatches).await;
+            counters
+                .checksum_errors
+                .update(&stats.checksum_errors)
+                .await;
+            counters.collisions.update(&stats.collisions).await;
+            counters.unknown_srqs.update(&stats.unknown_srqs).await;
+            counters.imm_pending.update(&stats.imm_pending).await;
+            counters.que_pending.update(&stats.que_pending).await;
+            counters.imm_wait.take(&stats.imm_wait_ms).await;
+            counters.que_wait.take(&stats.que_wait_ms).await;
+        }
+    }
+
+    pub async fn run(mut self) -> ! {
+        let (bcast, cmds) = (&self.bcast, &mut self.commands);
+        join!(
+            Self::update(&self.stats, &mut self.counters),
+            cmds.out_ena.run(bcast),
+            cmds.volt_set.run(bcast),
+            cmds.curr_set.run(bcast),
+            cmds.reset.run(bcast),
+            cmds.save.run(bcast),
+            cmds.recall.run(bcast),
+            Self::manage(&mut self.devices, &mut self.dev_add, &mut self.dev_remove),
+        );
+        unreachable!()
+    }
+}
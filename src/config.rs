@@ -0,0 +1,402 @@
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+
+use crate::{
+    device,
+    serial::{self, Addr},
+};
+
+/// Environment variable containing path to the configuration file.
+///
+/// Can be set from `st.cmd` using `epicsEnvSet` before `iocInit`.
+pub const PATH_VAR: &str = "TDK_LAMBDA_CONFIG";
+
+/// Maximal device address supported by the protocol.
+pub const MAX_ADDR: Addr = 30;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Environment variable {0} is not set")]
+    NoPath(&'static str),
+    #[error("Cannot read config '{0}': {1}")]
+    Read(PathBuf, io::Error),
+    #[error("Cannot parse config '{0}': {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "bus")]
+    pub buses: Vec<Bus>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Bus {
+    /// Bus name, used in log messages.
+    pub name: String,
+    /// Prefix prepended to default PV prefixes of bus devices.
+    #[serde(default)]
+    pub prefix: String,
+    pub transport: Transport,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub timing: Timing,
+    #[serde(default)]
+    pub framing: Framing,
+    #[serde(default)]
+    pub schedule: Schedule,
+    /// Sweep the whole address range at startup and serve found devices too.
+    #[serde(default)]
+    pub discover: bool,
+    #[serde(rename = "device", default)]
+    pub devices: Vec<Device>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Transport {
+    /// Terminal server.
+    Tcp { endpoint: String },
+    /// Local serial port.
+    Serial { path: String, baud_rate: u32 },
+    /// Built-in emulator of configured devices.
+    Emulator,
+}
+
+/// Protocol of devices on the bus.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// TDK-Lambda Genesys and compatible supplies.
+    #[default]
+    Genesys,
+    /// Lambda ZUP supplies.
+    Zup,
+    /// SCPI over LAN interface of Genesys+ and Z+ supplies.
+    Scpi,
+}
+
+impl Protocol {
+    /// Protocol to communicate over the bus.
+    pub fn build(self, framing: &Framing) -> Arc<dyn serial::Protocol> {
+        match self {
+            Protocol::Genesys => Arc::new(serial::Genesys {
+                checksum: framing.checksum,
+            }),
+            Protocol::Zup => Arc::new(serial::Zup),
+            Protocol::Scpi => Arc::new(serial::Scpi),
+        }
+    }
+
+    /// Type of devices on the bus.
+    pub fn model(self) -> device::Model {
+        match self {
+            Protocol::Genesys => device::Model::Genesys,
+            Protocol::Zup => device::Model::Zup,
+            Protocol::Scpi => device::Model::Scpi,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timing {
+    /// Delay before each command in milliseconds.
+    pub delay_ms: u64,
+    /// Response timeout in milliseconds.
+    pub timeout_ms: u64,
+    /// Number of attempts to send command.
+    pub retries: usize,
+    /// Default time in milliseconds for command to wait for execution before it's dropped.
+    pub expiry_ms: u64,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            delay_ms: 10,
+            timeout_ms: 200,
+            retries: 2,
+            expiry_ms: 5000,
+        }
+    }
+}
+
+impl From<&Timing> for serial::Timing {
+    fn from(timing: &Timing) -> Self {
+        Self {
+            delay: Duration::from_millis(timing.delay_ms),
+            timeout: Duration::from_millis(timing.timeout_ms),
+            retries: timing.retries,
+            expiry: Duration::from_millis(timing.expiry_ms),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Framing {
+    /// Use `$XX` checksum suffix in commands and responses.
+    pub checksum: bool,
+    /// Half-duplex adapter echoes transmitted bytes back.
+    pub echo: bool,
+}
+
+impl From<&Framing> for serial::Framing {
+    fn from(framing: &Framing) -> Self {
+        Self {
+            checksum: framing.checksum,
+            echo: framing.echo,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Schedule {
+    /// Number of immediate commands (writes) executed in a row while queued ones (reads) are waiting.
+    pub immediate_burst: usize,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self { immediate_burst: 4 }
+    }
+}
+
+impl From<&Schedule> for serial::Schedule {
+    fn from(schedule: &Schedule) -> Self {
+        Self {
+            immediate_burst: schedule.immediate_burst,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    /// Detect at startup.
+    #[default]
+    Auto,
+    /// Firmware responding `ON`/`OFF` to `OUT?`.
+    Old,
+    /// Firmware responding `1`/`0` to `OUT?`.
+    New,
+}
+
+impl Dialect {
+    /// Dialect if it's set explicitly.
+    pub fn fixed(self) -> Option<device::Dialect> {
+        match self {
+            Dialect::Auto => None,
+            Dialect::Old => Some(device::Dialect::Old),
+            Dialect::New => Some(device::Dialect::New),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scan {
+    /// Scan period of device readbacks in milliseconds.
+    pub period_ms: u64,
+    /// Scan periods of specific readbacks in milliseconds, overrides `period_ms`.
+    pub param_ms: HashMap<String, u64>,
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        Self {
+            period_ms: 1000,
+            param_ms: HashMap::new(),
+        }
+    }
+}
+
+impl Scan {
+    /// Scan periods of readbacks.
+    pub fn periods(&self) -> device::Scan {
+        let period =
+            |name: &str| Duration::from_millis(*self.param_ms.get(name).unwrap_or(&self.period_ms));
+        device::Scan {
+            volt_real: period("volt_real"),
+            curr_real: period("curr_real"),
+            mode: period("mode"),
+            status: period("status"),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.period_ms == 0 || self.param_ms.values().any(|&ms| ms == 0) {
+            return Err("Scan period must be positive".into());
+        }
+        for name in self.param_ms.keys() {
+            if !device::Scan::PARAMS.contains(&name.as_str()) {
+                return Err(format!(
+                    "Unknown scanned parameter '{}', expected one of {:?}",
+                    name,
+                    device::Scan::PARAMS
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Device {
+    pub addr: Addr,
+    #[serde(default)]
+    pub dialect: Dialect,
+    #[serde(default)]
+    pub scan: Scan,
+    /// Prefix of device PVs, `{bus.prefix}PS{addr}:` by default.
+    prefix: Option<String>,
+}
+
+impl Device {
+    /// Device with default settings.
+    pub fn new(addr: Addr) -> Self {
+        Self {
+            addr,
+            dialect: Dialect::default(),
+            scan: Scan::default(),
+            prefix: None,
+        }
+    }
+}
+
+impl Bus {
+    /// Configured device with given address or default one.
+    pub fn device(&self, addr: Addr) -> Device {
+        self.devices
+            .iter()
+            .find(|dev| dev.addr == addr)
+            .cloned()
+            .unwrap_or_else(|| Device::new(addr))
+    }
+
+    /// Prefix of bus-level PVs.
+    pub fn records_prefix(&self) -> String {
+        format!("{}:", self.name)
+    }
+
+    pub fn device_prefix(&self, dev: &Device) -> String {
+        match &dev.prefix {
+            Some(prefix) => prefix.clone(),
+            None => format!("{}PS{}:", self.prefix, dev.addr),
+        }
+    }
+}
+
+impl Config {
+    /// Load config from file which path is stored in [`PATH_VAR`] environment variable.
+    pub fn from_env() -> Result<Self, Error> {
+        let path = env::var_os(PATH_VAR).ok_or(Error::NoPath(PATH_VAR))?;
+        Self::from_file(path)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        log::info!("Load config from '{}'", path.display());
+        let text = fs::read_to_string(path).map_err(|e| Error::Read(path.into(), e))?;
+        let config: Self = toml::from_str(&text).map_err(|e| Error::Parse(path.into(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        if self.buses.is_empty() {
+            return Err(Error::Invalid("No buses specified".into()));
+        }
+        let mut names = HashSet::new();
+        let mut prefixes = HashSet::new();
+        for bus in &self.buses {
+            if !names.insert(bus.name.as_str()) {
+                return Err(Error::Invalid(format!("Duplicate bus name '{}'", bus.name)));
+            }
+            bus.validate()?;
+            let dev_prefixes = bus.devices.iter().map(|dev| bus.device_prefix(dev));
+            for prefix in [bus.records_prefix()].into_iter().chain(dev_prefixes) {
+                if !prefixes.insert(prefix.clone()) {
+                    return Err(Error::Invalid(format!(
+                        "Bus '{}': Duplicate PV prefix '{}', set bus or device prefix to resolve",
+                        bus.name, prefix
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bus {
+    fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: String| Error::Invalid(format!("Bus '{}': {}", self.name, msg));
+        if let Transport::Serial { baud_rate: 0, .. } = self.transport {
+            return Err(invalid("Baud rate must be positive".into()));
+        }
+        match self.protocol {
+            Protocol::Genesys => (),
+            Protocol::Zup => {
+                if let Transport::Emulator = self.transport {
+                    return Err(invalid("Emulator supports Genesys protocol only".into()));
+                }
+            }
+            Protocol::Scpi => {
+                if !matches!(self.transport, Transport::Tcp { .. }) {
+                    return Err(invalid("SCPI protocol requires TCP transport".into()));
+                }
+            }
+        }
+        if self.protocol != Protocol::Genesys && self.framing.checksum {
+            return Err(invalid(format!(
+                "{:?} protocol has no checksum",
+                self.protocol
+            )));
+        }
+        if self.timing.retries == 0 {
+            return Err(invalid("Number of retries must be positive".into()));
+        }
+        if self.timing.expiry_ms == 0 {
+            return Err(invalid("Expiry time must be positive".into()));
+        }
+        if self.schedule.immediate_burst == 0 {
+            return Err(invalid("Immediate burst must be positive".into()));
+        }
+        if self.devices.is_empty() && !self.discover {
+            return Err(invalid(
+                "No devices specified and discovery is disabled".into(),
+            ));
+        }
+        let mut addrs = HashSet::new();
+        for dev in &self.devices {
+            if dev.addr > MAX_ADDR {
+                return Err(invalid(format!(
+                    "Device address {} is out of range 0..={}",
+                    dev.addr, MAX_ADDR
+                )));
+            }
+            if !addrs.insert(dev.addr) {
+                return Err(invalid(format!("Duplicate device address {}", dev.addr)));
+            }
+            dev.scan
+                .validate()
+                .map_err(|msg| invalid(format!("Device {}: {}", dev.addr, msg)))?;
+        }
+        Ok(())
+    }
+}
@@ -1,162 +1,523 @@
 mod param;
 pub mod parser;
+mod status;
 
+pub use param::take;
 use param::*;
 use parser::*;
+use status::*;
 
 use ferrite::{variable::*, Context};
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    fmt::Debug,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 use thiserror::Error;
-use tokio::{join, runtime};
+use tokio::{
+    join, select,
+    sync::{watch::error::RecvError, Notify},
+    time::{interval, Interval, MissedTickBehavior},
+};
 
-use crate::serial::{Handle, Priority};
+use crate::serial::{self, Commander, Handle, Online, Priority};
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Variable isn't ready to process")]
     VarNotReady,
-    #[error("No response from device")]
-    NoResponse,
     #[error("Unexpected response: {0}")]
     Parse(String),
+    #[error("{0}")]
+    Serial(#[from] serial::Error),
 }
 
-pub trait ParserBool: Parser<u16> + Default + Send + 'static {}
-impl<P: Parser<u16> + Default + Send + 'static> ParserBool for P {}
+/// Protocol dialect, depends on device firmware.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// Switches are represented as `ON`/`OFF`.
+    Old,
+    /// Switches are represented as `1`/`0`.
+    #[default]
+    New,
+}
+
+impl Dialect {
+    /// Dialect of Genesys firmware by manufacturer in `IDN?` response, `None` if it's unknown.
+    ///
+    /// Supplies made before rebranding to TDK-Lambda (`LAMBDA,GEN10-240`) use the old dialect.
+    pub fn from_ident(ident: &str) -> Option<Self> {
+        let vendor = ident.split(',').next().unwrap_or("").trim();
+        if vendor.eq_ignore_ascii_case("LAMBDA") {
+            Some(Dialect::Old)
+        } else if vendor.eq_ignore_ascii_case("TDK-LAMBDA") {
+            Some(Dialect::New)
+        } else {
+            None
+        }
+    }
+}
 
-struct Params<B: ParserBool> {
+/// Device type, defines its command set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Model {
+    /// Genesys and compatible supplies.
+    #[default]
+    Genesys,
+    /// Lambda ZUP, switches are represented as `1`/`0`.
+    Zup,
+    /// Genesys+ and Z+ over SCPI, switches are represented as `ON`/`OFF`.
+    Scpi,
+}
+
+/// Scan periods of readbacks.
+#[derive(Debug, Clone)]
+pub struct Scan {
+    pub volt_real: Duration,
+    pub curr_real: Duration,
+    pub mode: Duration,
+    pub status: Duration,
+}
+
+impl Scan {
+    /// Names of scanned parameters.
+    pub const PARAMS: &'static [&'static str] = &["volt_real", "curr_real", "mode", "status"];
+}
+
+struct Params {
     pub ser_numb: Param<String, StringParser, ArrayVariable<u8, false, true, true>>,
-    pub out_ena: Param<u16, B, Variable<u16, true, true, false>>,
+    pub out_ena: Param<u16, SwitchParser, Variable<u16, true, true, false>>,
     pub volt_real: Param<f64, NumParser, Variable<f64, false, true, true>>,
     pub curr_real: Param<f64, NumParser, Variable<f64, false, true, true>>,
+    /// Not supported by ZUP.
+    pub mode: Option<Param<u16, ModeParser, Variable<u16, false, true, true>>>,
+    /// Supported by Genesys only.
+    pub status: Option<Status>,
+    pub srq: Srq,
     pub over_volt_set_point: Param<f64, NumParser, Variable<f64, true, true, false>>,
     pub under_volt_set_point: Param<f64, NumParser, Variable<f64, true, true, false>>,
     pub volt_set: Param<f64, NumParser, Variable<f64, true, true, false>>,
     pub curr_set: Param<f64, NumParser, Variable<f64, true, true, false>>,
 }
 
-impl<B: ParserBool> Params<B> {
-    pub fn new(epics: &mut Context, prefix: &str) -> Self {
+impl Params {
+    pub fn new(epics: &mut Context, prefix: &str, model: Model) -> Self {
+        let name = |name: &str| format!("{}{}", prefix, name);
+        // Command of each model, ZUP has no serial number, so model name is read instead,
+        // SCPI serial number is the third field of identity.
+        let cmd = |genesys: &str, zup: (&str, char, &'static str), scpi: &str| match model {
+            Model::Genesys => Command::genesys(genesys),
+            Model::Zup => Command::zup(zup.0, zup.1, zup.2),
+            Model::Scpi => Command::scpi(scpi),
+        };
+        // ZUP requires fixed number format.
+        let num = |precision: usize| match model {
+            Model::Genesys | Model::Scpi => NumParser::default(),
+            Model::Zup => NumParser::fixed(precision),
+        };
         Self {
-            ser_numb: Param::new("SN", epics, &format!("{}ser_numb", prefix), StringParser),
-            out_ena: Param::new("OUT", epics, &format!("{}out_ena", prefix), B::default()),
-            volt_real: Param::new("MV", epics, &format!("{}volt_real", prefix), NumParser),
-            curr_real: Param::new("MC", epics, &format!("{}curr_real", prefix), NumParser),
+            ser_numb: Param::new(
+                cmd("SN", ("MDL", '?', ""), "*IDN"),
+                epics,
+                &name("ser_numb"),
+                match model {
+                    Model::Genesys | Model::Zup => StringParser::default(),
+                    Model::Scpi => StringParser::field(2),
+                },
+            ),
+            out_ena: Param::new(
+                cmd("OUT", ("OUT", '?', "OT"), ":OUTP:STAT"),
+                epics,
+                &name("out_ena"),
+                SwitchParser::default(),
+            ),
+            volt_real: Param::new(
+                cmd("MV", ("VOL", '!', "AV"), ":MEAS:VOLT"),
+                epics,
+                &name("volt_real"),
+                num(3),
+            ),
+            curr_real: Param::new(
+                cmd("MC", ("CUR", '!', "AA"), ":MEAS:CURR"),
+                epics,
+                &name("curr_real"),
+                num(2),
+            ),
+            mode: match model {
+                Model::Genesys => Some(Command::genesys("MODE")),
+                Model::Zup => None,
+                Model::Scpi => Some(Command::scpi(":SOUR:MODE")),
+            }
+            .map(|cmd| Param::new(cmd, epics, &name("mode"), ModeParser)),
+            status: match model {
+                Model::Genesys => Some(Status::new(epics, prefix)),
+                Model::Zup | Model::Scpi => None,
+            },
+            srq: Srq::new(epics, prefix),
             over_volt_set_point: Param::new(
-                "OVP",
+                cmd("OVP", ("OVP", '?', "OP"), ":VOLT:PROT:LEV"),
                 epics,
-                &format!("{}over_volt_set_point", prefix),
-                NumParser,
+                &name("over_volt_set_point"),
+                num(2),
             ),
             under_volt_set_point: Param::new(
-                "UVL",
+                cmd("UVL", ("UVP", '?', "UP"), ":VOLT:LIM:LOW"),
+                epics,
+                &name("under_volt_set_point"),
+                num(2),
+            ),
+            volt_set: Param::new(
+                cmd("PV", ("VOL", '?', "SV"), ":VOLT"),
+                epics,
+                &name("volt_set"),
+                num(3),
+            ),
+            curr_set: Param::new(
+                cmd("PC", ("CUR", '?', "SA"), ":CURR"),
                 epics,
-                &format!("{}under_volt_set_point", prefix),
-                NumParser,
+                &name("curr_set"),
+                num(2),
             ),
-            volt_set: Param::new("PV", epics, &format!("{}volt_set", prefix), NumParser),
-            curr_set: Param::new("PC", epics, &format!("{}curr_set", prefix), NumParser),
         }
     }
 }
 
-pub struct Device<B: ParserBool> {
-    addr: u8,
-    params: Params<B>,
-    serial: Handle,
+/// Check that EPICS records for device with given prefix exist.
+pub fn has_records(epics: &Context, prefix: &str) -> bool {
+    epics.registry.contains_key(&format!("{}ser_numb", prefix))
 }
 
-pub type DeviceOld = Device<parser::BoolParser>;
-pub type DeviceNew = Device<parser::NumParser>;
+pub struct Device {
+    /// Device name for log messages.
+    name: String,
+    /// Dialect set in config, detected at startup if `None`.
+    dialect: Option<Dialect>,
+    scan: Scan,
+    params: Params,
+    /// Status record, whether device responds.
+    online: Variable<u16, false, true, true>,
+    serial: Handle,
+}
 
-impl<B: ParserBool> Device<B> {
-    pub fn new(addr: u8, epics: &mut Context, serial: Handle) -> Self {
-        let prefix = format!("PS{}:", addr);
+impl Device {
+    pub fn new(
+        prefix: &str,
+        model: Model,
+        dialect: Option<Dialect>,
+        scan: Scan,
+        epics: &mut Context,
+        serial: Handle,
+    ) -> Self {
         Self {
-            addr,
+            name: String::from(prefix.trim_end_matches(':')),
+            dialect: match model {
+                Model::Genesys => dialect,
+                Model::Zup => Some(Dialect::New),
+                Model::Scpi => Some(Dialect::Old),
+            },
+            scan,
             serial,
-            params: Params::new(epics, &prefix),
+            params: Params::new(epics, prefix, model),
+            online: take(epics, &format!("{}online", prefix)),
+        }
+    }
+
+    /// Detect protocol dialect by device identity, or by format of `OUT?` response if identity is unknown.
+    async fn detect_dialect(cmdr: &Commander) -> Result<Dialect, Error> {
+        let ident = cmdr
+            .execute(String::from("IDN?"), Priority::queued())
+            .await?;
+        if let Some(dialect) = Dialect::from_ident(&ident) {
+            return Ok(dialect);
+        }
+        log::debug!("Unknown identity '{}', probe dialect by `OUT?`", ident);
+        let resp = cmdr
+            .execute(String::from("OUT?"), Priority::queued())
+            .await?;
+        match resp.as_str() {
+            "OFF" | "ON" => Ok(Dialect::Old),
+            "0" | "1" => Ok(Dialect::New),
+            _ => Err(Error::Parse(resp)),
         }
     }
 }
 
-macro_rules! async_loop {
-    (($($bdst:ident = $bsrc:expr),*), $code:block) => {{
-        #[allow(unused_parens)]
-        let ($($bdst),*) = ($($bsrc.clone()),*);
-        async move {
-            loop {
-                $code
-            }
+/// Request for monitors to stop, e.g. to initialize device again.
+///
+/// Monitors stop only between accesses to records, so that no record is left in processing.
+#[derive(Default)]
+struct Stop {
+    flag: AtomicBool,
+    notify: Notify,
+}
+
+impl Stop {
+    fn set(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+        self.notify.notify_waiters();
+    }
+
+    /// Wait until stop is requested.
+    async fn wait(&self) {
+        let notified = self.notify.notified();
+        if !self.flag.load(Ordering::Relaxed) {
+            notified.await;
         }
-    }};
+    }
 }
 
-impl<B: ParserBool> Device<B> {
-    pub async fn run(self) -> ! {
-        let rt = runtime::Handle::current();
-        let mut params = self.params;
-        let cmdr = Arc::new(self.serial.req);
+/// Requests to read registers and readbacks immediately.
+#[derive(Default)]
+struct Refresh {
+    status: Notify,
+    volt_real: Notify,
+    curr_real: Notify,
+    mode: Notify,
+}
+
+impl Refresh {
+    fn notify(&self) {
+        for notify in [&self.status, &self.volt_real, &self.curr_real, &self.mode] {
+            notify.notify_one();
+        }
+    }
+}
 
-        rt.spawn(async_loop!((intr = self.serial.intr), {
-            intr.notified().await;
-            log::warn!("PS{}: Interrupt caught!", self.addr);
-        }));
+impl Device {
+    async fn init(&mut self) {
+        let params = &mut self.params;
+        let cmdr = &self.serial.req;
 
-        log::debug!("PS{}: Initialize", self.addr);
+        let dialect = match self.dialect {
+            Some(dialect) => dialect,
+            None => match Self::detect_dialect(cmdr).await {
+                Ok(dialect) => {
+                    log::info!("{}: Detected dialect: {:?}", self.name, dialect);
+                    dialect
+                }
+                Err(err) => {
+                    let dialect = Dialect::default();
+                    log::warn!(
+                        "{}: Cannot detect dialect, assume {:?}: {}",
+                        self.name,
+                        dialect,
+                        err
+                    );
+                    dialect
+                }
+            },
+        };
+        params.out_ena.parser_mut().dialect = dialect;
+
+        log::debug!("{}: Initialize", self.name);
         join!(
-            params.ser_numb.read_or_log(&cmdr, Priority::Queued),
-            params.out_ena.init_or_log(&cmdr, Priority::Queued),
-            params.volt_set.init_or_log(&cmdr, Priority::Queued),
-            params.curr_set.init_or_log(&cmdr, Priority::Queued),
+            params.ser_numb.read_or_log(cmdr, Priority::queued()),
+            params.out_ena.init_or_log(cmdr, Priority::queued()),
+            params.volt_set.init_or_log(cmdr, Priority::queued()),
+            params.curr_set.init_or_log(cmdr, Priority::queued()),
             params
                 .over_volt_set_point
-                .init_or_log(&cmdr, Priority::Queued),
+                .init_or_log(cmdr, Priority::queued()),
             params
                 .under_volt_set_point
-                .init_or_log(&cmdr, Priority::Queued),
+                .init_or_log(cmdr, Priority::queued()),
         );
-        cmdr.yield_();
+    }
 
-        log::debug!("PS{}: Start monitors", self.addr);
-        rt.spawn(async_loop!((cmdr = cmdr), {
-            params
-                .out_ena
-                .write_or_log(&cmdr, Priority::Immediate)
-                .await;
-        }));
-        rt.spawn(async_loop!((cmdr = cmdr), {
-            params
-                .volt_set
-                .write_or_log(&cmdr, Priority::Immediate)
-                .await;
-        }));
-        rt.spawn(async_loop!((cmdr = cmdr), {
-            params
-                .curr_set
-                .write_or_log(&cmdr, Priority::Immediate)
-                .await;
-        }));
-        rt.spawn(async_loop!((cmdr = cmdr), {
+    /// Serve device until stop is requested.
+    async fn monitor(
+        params: &mut Params,
+        scan: &Scan,
+        cmdr: &Commander,
+        intr: &Notify,
+        stop: &Stop,
+    ) {
+        let refresh = Refresh::default();
+        join!(
+            // Service request means that status has changed.
+            async {
+                loop {
+                    select! {
+                        () = intr.notified() => (),
+                        () = stop.wait() => break,
+                    }
+                    params.srq.caught().await;
+                    refresh.notify();
+                }
+            },
+            params.out_ena.write_loop(cmdr, Priority::Immediate, stop),
+            params.volt_set.write_loop(cmdr, Priority::Immediate, stop),
+            params.curr_set.write_loop(cmdr, Priority::Immediate, stop),
             params
                 .over_volt_set_point
-                .write_or_log(&cmdr, Priority::Immediate)
-                .await;
-        }));
-        rt.spawn(async_loop!((cmdr = cmdr), {
+                .write_loop(cmdr, Priority::Immediate, stop),
             params
                 .under_volt_set_point
-                .write_or_log(&cmdr, Priority::Immediate)
-                .await;
-        }));
+                .write_loop(cmdr, Priority::Immediate, stop),
+            // Readback is useless after the next scan, so it expires then.
+            async {
+                let cmdr = cmdr.with_expiry(scan.volt_real);
+                let mut timer = scan_timer(scan.volt_real);
+                while let Some(priority) =
+                    next_read(&mut timer, scan.volt_real, &refresh.volt_real, stop).await
+                {
+                    params.volt_real.read_or_log(&cmdr, priority).await;
+                }
+            },
+            async {
+                let cmdr = cmdr.with_expiry(scan.curr_real);
+                let mut timer = scan_timer(scan.curr_real);
+                while let Some(priority) =
+                    next_read(&mut timer, scan.curr_real, &refresh.curr_real, stop).await
+                {
+                    params.curr_real.read_or_log(&cmdr, priority).await;
+                }
+            },
+            async {
+                let mode = match &mut params.mode {
+                    Some(mode) => mode,
+                    None => return,
+                };
+                let cmdr = cmdr.with_expiry(scan.mode);
+                let mut timer = scan_timer(scan.mode);
+                while let Some(priority) =
+                    next_read(&mut timer, scan.mode, &refresh.mode, stop).await
+                {
+                    mode.read_or_log(&cmdr, priority).await;
+                }
+            },
+            async {
+                let status = match &mut params.status {
+                    Some(status) => status,
+                    None => return,
+                };
+                let cmdr = cmdr.with_expiry(scan.status);
+                let mut timer = scan_timer(scan.status);
+                while let Some(priority) =
+                    next_read(&mut timer, scan.status, &refresh.status, stop).await
+                {
+                    status.read_or_log(&cmdr, priority).await;
+                }
+            },
+        );
+    }
+
+    /// Mark readback variables as invalid.
+    async fn invalidate(params: &mut Params, msg: &str) {
+        join!(
+            params.volt_real.invalidate(msg),
+            params.curr_real.invalidate(msg),
+            async {
+                if let Some(mode) = &mut params.mode {
+                    mode.invalidate(msg).await;
+                }
+            },
+            async {
+                if let Some(status) = &mut params.status {
+                    status.invalidate(msg).await;
+                }
+            },
+        );
+    }
+
+    /// Attach device to new multiplexer client after it has been removed.
+    pub fn attach(&mut self, serial: Handle) {
+        self.serial = serial;
+    }
 
-        log::debug!("PS{}: Enter scan loop", self.addr);
+    /// Serve device until it's removed from multiplexer.
+    ///
+    /// Returns device to attach it again later.
+    pub async fn run(mut self) -> Self {
         loop {
-            join!(
-                params.volt_real.read_or_log(&cmdr, Priority::Queued),
-                params.curr_real.read_or_log(&cmdr, Priority::Queued),
+            if wait_online(&mut self.serial.online, true).await.is_err() {
+                break;
+            }
+            self.online.request().await.write(1).await;
+            self.init().await;
+
+            log::debug!("{}: Start monitors", self.name);
+            let stop = Stop::default();
+            let (online, changed) = (&mut self.serial.online, &self.serial.changed);
+            let ((), offline) = join!(
+                Self::monitor(
+                    &mut self.params,
+                    &self.scan,
+                    &self.serial.req,
+                    &self.serial.intr,
+                    &stop,
+                ),
+                async {
+                    let offline = select! {
+                        res = wait_online(online, false) => Some(res),
+                        // Read settings changed by broadcast command.
+                        () = changed.notified() => None,
+                    };
+                    // Let monitors finish pending writes.
+                    stop.set();
+                    offline
+                },
             );
-            cmdr.yield_();
+            match offline {
+                Some(Ok(())) => {
+                    log::warn!("{}: Offline", self.name);
+                    self.online.request().await.write(0).await;
+                    Self::invalidate(&mut self.params, "Device is offline").await;
+                }
+                Some(Err(_)) => break,
+                None => (),
+            }
         }
+
+        log::info!("{}: Removed", self.name);
+        self.online.request().await.write(0).await;
+        Self::invalidate(&mut self.params, "Device is removed").await;
+        self
+    }
+}
+
+/// Timer ticking with given period, each reading should be done until the next tick.
+fn scan_timer(period: Duration) -> Interval {
+    let mut timer = interval(period);
+    // Don't burst if bus is overloaded.
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    timer
+}
+
+/// Wait for the next scan or immediate refresh, returns priority of reading or `None` on stop.
+async fn next_read(
+    timer: &mut Interval,
+    period: Duration,
+    refresh: &Notify,
+    stop: &Stop,
+) -> Option<Priority> {
+    select! {
+        tick = timer.tick() => Some(Priority::Queued(tick + period)),
+        () = refresh.notified() => Some(Priority::Immediate),
+        () = stop.wait() => None,
+    }
+}
+
+/// Wait for online state, fails if device has been removed from multiplexer.
+async fn wait_online(online: &mut Online, value: bool) -> Result<(), RecvError> {
+    while *online.borrow_and_update() != value {
+        online.changed().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dialect_from_ident() {
+        assert_eq!(Dialect::from_ident("LAMBDA,GEN10-240"), Some(Dialect::Old));
+        assert_eq!(
+            Dialect::from_ident("TDK-LAMBDA,GEN60-12"),
+            Some(Dialect::New)
+        );
+        assert_eq!(Dialect::from_ident("TDK-Lambda Emulator"), None);
+        assert_eq!(Dialect::from_ident(""), None);
     }
 }
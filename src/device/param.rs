@@ -1,11 +1,93 @@
 use ferrite::{variable::*, Context};
 use std::{fmt::Display, str::FromStr};
+use tokio::select;
 
-use super::{Error, Parser};
-use crate::serial::{Commander, Priority};
+use super::{Error, Parser, Stop};
+use crate::serial::{self, Commander, Priority};
+
+/// Take variable of given type from registry.
+pub fn take<V: Var>(epics: &mut Context, name: &str) -> V
+where
+    AnyVariable: Downcast<V>,
+{
+    let any = epics
+        .registry
+        .remove(name)
+        .unwrap_or_else(|| panic!("No such name: {}", name));
+    let info = any.info();
+    any.downcast()
+        .unwrap_or_else(|| panic!("Bad type, {:?} expected", info))
+}
+
+/// Device commands to access parameter.
+#[derive(Debug, Clone)]
+pub struct Command {
+    /// Parameter name in device protocol, also used to coalesce writes.
+    name: String,
+    query: String,
+    /// Setting command is the value between `set` and `end`.
+    set: String,
+    end: &'static str,
+    /// Part of query response preceding value.
+    prefix: &'static str,
+    /// Response to accepted setting.
+    ack: &'static str,
+}
+
+impl Command {
+    /// Genesys command, e.g. `PV?` query and `PV 1.5` setting answered with `OK`.
+    pub fn genesys(name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            query: format!("{}?", name),
+            set: format!("{} ", name),
+            end: "",
+            prefix: "",
+            ack: "OK",
+        }
+    }
+
+    /// ZUP command, e.g. `:VOL?;` query answered with `SV1.500` and `:VOL1.500;` setting without answer.
+    ///
+    /// Query is marked by `?` or `!` depending on whether setting or actual value is read.
+    pub fn zup(name: &str, query: char, prefix: &'static str) -> Self {
+        Self {
+            name: String::from(name),
+            query: format!(":{}{};", name, query),
+            set: format!(":{}", name),
+            end: ";",
+            prefix,
+            ack: "",
+        }
+    }
+
+    /// SCPI command, e.g. `:VOLT?` query and `:VOLT 1.5` setting without answer.
+    pub fn scpi(name: &str) -> Self {
+        Self {
+            name: String::from(name),
+            query: format!("{}?", name),
+            set: format!("{} ", name),
+            end: "",
+            prefix: "",
+            ack: "",
+        }
+    }
+
+    fn setting(&self, value: &str) -> String {
+        format!("{}{}{}", self.set, value, self.end)
+    }
+
+    /// Value from query response.
+    fn value(&self, resp: String) -> Result<String, Error> {
+        match resp.strip_prefix(self.prefix) {
+            Some(value) => Ok(String::from(value)),
+            None => Err(Error::Parse(resp)),
+        }
+    }
+}
 
 pub struct Param<T, P: Parser<T>, V: Var> {
-    cmd: String,
+    cmd: Command,
     var: V,
     parser: P,
     value: Option<T>,
@@ -15,19 +97,11 @@ impl<T, P: Parser<T>, V: Var> Param<T, P, V>
 where
     AnyVariable: Downcast<V>,
 {
-    pub fn new(cmd: &str, epics: &mut Context, name: &str, parser: P) -> Self {
+    pub fn new(cmd: Command, epics: &mut Context, name: &str, parser: P) -> Self {
         log::trace!("parameter: {}", name);
-        let any = epics
-            .registry
-            .remove(name)
-            .unwrap_or_else(|| panic!("No such name: {}", name));
-        let info = any.info();
-        let var = any
-            .downcast()
-            .unwrap_or_else(|| panic!("Bad type, {:?} expected", info));
         Self {
-            cmd: String::from(cmd),
-            var,
+            cmd,
+            var: take(epics, name),
             parser,
             value: None,
         }
@@ -35,8 +109,12 @@ where
 }
 
 impl<T, P: Parser<T>, V: Var> Param<T, P, V> {
+    pub fn parser_mut(&mut self) -> &mut P {
+        &mut self.parser
+    }
+
     fn log_err(&self, err: Error) {
-        log::error!("({}, {}) error: {}", self.cmd.clone(), self.var.name(), err);
+        log::error!("({}, {}) error: {}", self.cmd.name, self.var.name(), err);
     }
 }
 
@@ -44,9 +122,10 @@ impl<T: Copy + FromStr, P: Parser<T>, const R: bool, const A: bool>
     Param<T, P, Variable<T, R, true, A>>
 {
     async fn read_from_device(&mut self, cmdr: &Commander, priority: Priority) -> Result<T, Error> {
-        let cmd = format!("{}?", self.cmd);
-        let cmd_res = cmdr.execute(cmd, priority).await.ok_or(Error::NoResponse)?;
-        self.parser.load(cmd_res).map_err(Error::Parse)
+        let cmd_res = cmdr.execute(self.cmd.query.clone(), priority).await?;
+        self.parser
+            .load(self.cmd.value(cmd_res)?)
+            .map_err(Error::Parse)
     }
 
     pub async fn init(&mut self, cmdr: &Commander, priority: Priority) -> Result<(), Error> {
@@ -96,49 +175,61 @@ impl<T: Copy + FromStr, P: Parser<T>, const R: bool> Param<T, P, Variable<T, R,
             self.log_err(e);
         }
     }
+
+    /// Raise alarm without reading from device.
+    pub async fn invalidate(&mut self, msg: &str) {
+        self.var.request().await.reject(msg).await;
+    }
 }
 
 impl<T: Copy + Display, P: Parser<T>, const A: bool> Param<T, P, Variable<T, true, true, A>> {
-    pub async fn write(&mut self, cmdr: &Commander, priority: Priority) -> Result<(), Error> {
-        let mut var = self.var.acquire().await;
-        let value = *var;
-        let cmd = format!("{} {}", self.cmd, self.parser.store(value));
-        match cmdr
-            .execute(cmd.clone(), priority)
-            .await
-            .ok_or(Error::NoResponse)
-            .and_then(|cmd_res| match cmd_res.as_str() {
-                "OK" => Ok(()),
-                _ => Err(Error::Parse(cmd_res)),
+    /// Write values to device until stop is requested.
+    ///
+    /// Record is processed until device answers, so values put meanwhile are merged by EPICS into one reprocessing.
+    /// Stop is taken only while waiting for a new value, so that each acquired value is accepted or rejected.
+    pub async fn write_loop(&mut self, cmdr: &Commander, priority: Priority, stop: &Stop) {
+        loop {
+            let mut var = select! {
+                var = self.var.acquire() => var,
+                () = stop.wait() => break,
+            };
+            let value = *var;
+            let cmd = self.cmd.setting(&self.parser.store(value));
+            let res = match cmdr.submit_keyed(cmd, &self.cmd.name, priority) {
+                Ok(reply) => reply.await,
+                Err(err) => Err(err),
+            };
+            match res.map_err(Error::from).and_then(|cmd_res| {
+                if cmd_res == self.cmd.ack {
+                    Ok(())
+                } else {
+                    Err(Error::Parse(cmd_res))
+                }
             }) {
-            Ok(()) => {
-                self.value.replace(value);
-                var.accept().await;
-                Ok(())
-            }
-            Err(err) => {
-                if let Some(value) = self.value {
-                    *var = value;
+                Ok(()) => {
+                    self.value.replace(value);
+                    var.accept().await;
+                }
+                // Newer value is written instead, so this one is accepted as well.
+                Err(Error::Serial(serial::Error::Superseded)) => var.accept().await,
+                Err(err) => {
+                    if let Some(value) = self.value {
+                        *var = value;
+                    }
+                    var.reject(&format!("{}", err)).await;
+                    self.log_err(err);
                 }
-                var.reject(&format!("{}", err)).await;
-                Err(err)
             }
         }
     }
-
-    pub async fn write_or_log(&mut self, cmdr: &Commander, priority: Priority) {
-        if let Err(e) = self.write(cmdr, priority).await {
-            self.log_err(e);
-        }
-    }
 }
 
 impl<P: Parser<String>, const R: bool> Param<String, P, ArrayVariable<u8, R, true, true>> {
     pub async fn read(&mut self, cmdr: &Commander, priority: Priority) -> Result<(), Error> {
-        let cmd = format!("{}?", self.cmd);
+        let cmd_res = cmdr.execute(self.cmd.query.clone(), priority).await?;
         let value = self
             .parser
-            .load(cmdr.execute(cmd, priority).await.ok_or(Error::NoResponse)?)
+            .load(self.cmd.value(cmd_res)?)
             .map_err(Error::Parse)?;
         self.var
             .request()
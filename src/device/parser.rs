@@ -3,19 +3,34 @@ use std::{
     str::FromStr,
 };
 
+use super::Dialect;
+
 pub trait Parser<T> {
     fn load(&self, text: String) -> Result<T, String>;
     fn store(&self, value: T) -> String;
 }
 
+/// Parser of number, stored with fixed number of decimal places if `precision` is set.
 #[derive(Debug, Clone, Default)]
-pub struct NumParser;
+pub struct NumParser {
+    pub precision: Option<usize>,
+}
+impl NumParser {
+    pub fn fixed(precision: usize) -> Self {
+        Self {
+            precision: Some(precision),
+        }
+    }
+}
 impl<T: FromStr + Display> Parser<T> for NumParser {
     fn load(&self, text: String) -> Result<T, String> {
         text.parse::<T>().map_err(|_| text)
     }
     fn store(&self, value: T) -> String {
-        format!("{}", value)
+        match self.precision {
+            Some(precision) => format!("{:.*}", precision, value),
+            None => format!("{}", value),
+        }
     }
 }
 
@@ -34,13 +49,110 @@ impl Parser<u16> for BoolParser {
     }
 }
 
+/// Parser of switch state.
+///
+/// Accepts both representations and stores value in representation of the dialect.
 #[derive(Debug, Clone, Default)]
-pub struct StringParser;
+pub struct SwitchParser {
+    pub dialect: Dialect,
+}
+impl Parser<u16> for SwitchParser {
+    fn load(&self, text: String) -> Result<u16, String> {
+        match text.as_str() {
+            "OFF" | "0" => Ok(0),
+            "ON" | "1" => Ok(1),
+            _ => Err(text),
+        }
+    }
+    fn store(&self, value: u16) -> String {
+        match self.dialect {
+            Dialect::Old => BoolParser.store(value),
+            Dialect::New => Parser::<u16>::store(&NumParser::default(), value),
+        }
+    }
+}
+
+/// Operating mode of power supply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Mode {
+    /// Output is off.
+    Off = 0,
+    /// Constant voltage.
+    Cv = 1,
+    /// Constant current.
+    Cc = 2,
+}
+
+impl Mode {
+    const ALL: [Self; 3] = [Self::Off, Self::Cv, Self::Cc];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Off => "OFF",
+            Self::Cv => "CV",
+            Self::Cc => "CC",
+        }
+    }
+}
+
+/// Parser of operating mode, value is index of [`Mode`].
+#[derive(Debug, Clone, Default)]
+pub struct ModeParser;
+impl Parser<u16> for ModeParser {
+    fn load(&self, text: String) -> Result<u16, String> {
+        match Mode::ALL.into_iter().find(|mode| mode.name() == text) {
+            Some(mode) => Ok(mode as u16),
+            None => Err(text),
+        }
+    }
+    fn store(&self, value: u16) -> String {
+        match Mode::ALL.get(usize::from(value)) {
+            Some(mode) => String::from(mode.name()),
+            None => value.to_string(),
+        }
+    }
+}
+
+/// Parser of string, only takes comma-separated field with given index if `field` is set.
+#[derive(Debug, Clone, Default)]
+pub struct StringParser {
+    pub field: Option<usize>,
+}
+impl StringParser {
+    pub fn field(index: usize) -> Self {
+        Self { field: Some(index) }
+    }
+}
 impl Parser<String> for StringParser {
     fn load(&self, text: String) -> Result<String, String> {
-        Ok(text)
+        match self.field {
+            Some(index) => match text.split(',').nth(index) {
+                Some(field) => Ok(String::from(field.trim())),
+                None => Err(text),
+            },
+            None => Ok(text),
+        }
     }
     fn store(&self, value: String) -> String {
         value
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDN: &str = "TDK-LAMBDA,GEN8-400,123A456-0001,1U:4.1";
+
+    #[test]
+    fn string_field() {
+        let parser = StringParser::field(2);
+        assert_eq!(parser.load(String::from(IDN)).unwrap(), "123A456-0001");
+        assert!(parser.load(String::from("TDK-LAMBDA,GEN8-400")).is_err());
+        assert_eq!(
+            StringParser::default().load(String::from(IDN)).unwrap(),
+            IDN
+        );
+    }
+}
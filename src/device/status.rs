@@ -0,0 +1,213 @@
+use ferrite::{variable::*, Context};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{take, Error};
+use crate::serial::{Commander, Priority};
+
+/// Names of fault register bits, from the least significant one.
+pub const FAULT_BITS: [&str; 8] = ["", "AC", "OTP", "FOLD", "OVP", "SO", "OFF", "ENA"];
+
+/// Fault register bits which raise alarm on readbacks, `OFF` only means that output is switched off.
+///
+/// The same mask is used by `fault_alarm` record which passes alarm to readbacks.
+const ALARM_MASK: u8 = 0b1011_1110;
+
+/// Status and fault registers of device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub status: u8,
+    pub fault: u8,
+}
+
+impl Registers {
+    /// Parse `STT?` response, e.g. `MV(45.201),PV(45.000),MC(4.3257),PC(10.000),SR(30),FR(00)`.
+    pub fn parse(resp: &str) -> Option<Self> {
+        let field = |name: &str| {
+            resp.split(',').find_map(|field| {
+                let text = field
+                    .strip_prefix(name)?
+                    .strip_prefix('(')?
+                    .strip_suffix(')')?;
+                u8::from_str_radix(text, 16).ok()
+            })
+        };
+        Some(Self {
+            status: field("SR")?,
+            fault: field("FR")?,
+        })
+    }
+
+    /// Description of faults which raise alarm on readbacks, `None` if there are no such faults.
+    pub fn alarm(fault: u8) -> Option<String> {
+        let names = FAULT_BITS
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (fault & ALARM_MASK) & (1 << i) != 0)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            None
+        } else {
+            Some(format!("Fault: {}", names.join(", ")))
+        }
+    }
+}
+
+/// Status and fault register records, read by `STT?`.
+pub struct Status {
+    status: Variable<u16, false, true, true>,
+    fault: Variable<u16, false, true, true>,
+    /// Description of active faults, logged when it changes.
+    alarm: Option<String>,
+}
+
+impl Status {
+    pub fn new(epics: &mut Context, prefix: &str) -> Self {
+        Self {
+            status: take(epics, &format!("{}status", prefix)),
+            fault: take(epics, &format!("{}fault", prefix)),
+            alarm: None,
+        }
+    }
+
+    pub async fn read(&mut self, cmdr: &Commander, priority: Priority) -> Result<Registers, Error> {
+        let res = cmdr
+            .execute(String::from("STT?"), priority)
+            .await
+            .map_err(Error::from)
+            .and_then(|resp| Registers::parse(&resp).ok_or(Error::Parse(resp)));
+        match res {
+            Ok(regs) => {
+                self.status.request().await.write(regs.status.into()).await;
+                self.fault.request().await.write(regs.fault.into()).await;
+                self.log_alarm(Registers::alarm(regs.fault));
+                Ok(regs)
+            }
+            Err(err) => {
+                self.invalidate(&format!("{}", err)).await;
+                Err(err)
+            }
+        }
+    }
+
+    pub async fn read_or_log(&mut self, cmdr: &Commander, priority: Priority) {
+        if let Err(err) = self.read(cmdr, priority).await {
+            log::error!("(STT, {}) error: {}", self.status.name(), err);
+        }
+    }
+
+    fn log_alarm(&mut self, alarm: Option<String>) {
+        if alarm == self.alarm {
+            return;
+        }
+        match &alarm {
+            Some(msg) => log::warn!("{}: {}", self.fault.name(), msg),
+            None => log::info!("{}: Faults cleared", self.fault.name()),
+        }
+        self.alarm = alarm;
+    }
+
+    /// Raise alarm without reading from device.
+    pub async fn invalidate(&mut self, msg: &str) {
+        self.status.request().await.reject(msg).await;
+        self.fault.request().await.reject(msg).await;
+    }
+}
+
+/// Counter of service requests.
+#[derive(Debug, Clone, Copy, Default)]
+struct SrqCounter {
+    count: i32,
+}
+
+impl SrqCounter {
+    /// Count request caught at given time, returns number of requests and the time in seconds since Unix epoch.
+    fn caught(&mut self, time: SystemTime) -> (i32, f64) {
+        self.count = self.count.wrapping_add(1);
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        (self.count, secs)
+    }
+}
+
+/// Service request records.
+pub struct Srq {
+    count: Variable<i32, false, true, true>,
+    /// Time of the last request in seconds since Unix epoch.
+    time: Variable<f64, false, true, true>,
+    counter: SrqCounter,
+}
+
+impl Srq {
+    pub fn new(epics: &mut Context, prefix: &str) -> Self {
+        Self {
+            count: take(epics, &format!("{}srq_count", prefix)),
+            time: take(epics, &format!("{}srq_time", prefix)),
+            counter: SrqCounter::default(),
+        }
+    }
+
+    /// Count service request and record its time.
+    pub async fn caught(&mut self) {
+        let (count, time) = self.counter.caught(SystemTime::now());
+        self.count.request().await.write(count).await;
+        self.time.request().await.write(time).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_registers() {
+        let resp = "MV(45.201),PV(45.000),MC(4.3257),PC(10.000),SR(30),FR(1a)";
+        assert_eq!(
+            Registers::parse(resp),
+            Some(Registers {
+                status: 0x30,
+                fault: 0x1a
+            })
+        );
+    }
+
+    #[test]
+    fn parse_broken_registers() {
+        assert_eq!(Registers::parse("MV(45.201),SR(30)"), None);
+        assert_eq!(Registers::parse("SR(30),FR(xx)"), None);
+        assert_eq!(Registers::parse("SR(30),FR(00"), None);
+        assert_eq!(Registers::parse("C01"), None);
+    }
+
+    #[test]
+    fn alarm_of_faults() {
+        assert_eq!(Registers::alarm(0), None);
+        assert_eq!(Registers::alarm(0b0001_0010).unwrap(), "Fault: AC, OVP");
+        assert_eq!(Registers::alarm(0b1000_0000).unwrap(), "Fault: ENA");
+    }
+
+    #[test]
+    fn alarm_mask() {
+        // Unused bit and switched off output aren't faults.
+        assert_eq!(Registers::alarm(0b0100_0001), None);
+        assert_eq!(Registers::alarm(0b0101_0000).unwrap(), "Fault: OVP");
+        assert_eq!(ALARM_MASK.count_ones() as usize, FAULT_BITS.len() - 2);
+    }
+
+    #[test]
+    fn srq_count_and_time() {
+        let mut counter = SrqCounter::default();
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        assert_eq!(counter.caught(time), (1, 1.5));
+        assert_eq!(counter.caught(time).0, 2);
+    }
+
+    #[test]
+    fn srq_count_wraps() {
+        let mut counter = SrqCounter { count: i32::MAX };
+        assert_eq!(counter.caught(UNIX_EPOCH), (i32::MIN, 0.0));
+    }
+}
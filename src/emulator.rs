@@ -15,7 +15,7 @@ use tokio::{
     time::sleep,
 };
 
-use crate::serial::{Addr, LINE_TERM};
+use crate::serial::{checksum, Addr, DeviceError, Framing, LINE_TERM};
 
 type Pipe = AsyncHeapRb<u8>;
 type Writer = AsyncProducer<u8, Arc<Pipe>>;
@@ -24,11 +24,14 @@ type Reader = AsyncConsumer<u8, Arc<Pipe>>;
 pub struct Emulator {
     writer: Writer,
     reader: BufReader<Reader>,
+    framing: Framing,
     devs: HashMap<Addr, Device>,
+    /// Settings stored by `GSAV`.
+    saved: HashMap<Addr, Device>,
 }
 
 impl Emulator {
-    pub fn new<I: Iterator<Item = Addr>>(addrs: I) -> (Self, SerialPort) {
+    pub fn new<I: Iterator<Item = Addr>>(framing: Framing, addrs: I) -> (Self, SerialPort) {
         const LEN: usize = 32;
         let (fw, fr) = Pipe::new(LEN).split();
         let (bw, br) = Pipe::new(LEN).split();
@@ -40,6 +43,8 @@ impl Emulator {
                     .collect(),
                 reader: BufReader::new(fr),
                 writer: bw,
+                framing,
+                saved: HashMap::new(),
             },
             SerialPort {
                 writer: fw,
@@ -53,6 +58,9 @@ impl Emulator {
         loop {
             buf.clear();
             self.reader.read_until(LINE_TERM, &mut buf).await.unwrap();
+            if self.framing.echo {
+                self.writer.write_all(&buf).await.unwrap();
+            }
             assert!(buf.pop().unwrap() == LINE_TERM);
             if !buf.is_empty() {
                 break String::from_utf8(buf).unwrap();
@@ -61,10 +69,36 @@ impl Emulator {
     }
 
     async fn send(&mut self, msg: &str) {
+        let msg = if self.framing.checksum {
+            checksum::append(msg)
+        } else {
+            String::from(msg)
+        };
         self.writer.write_all(msg.as_bytes()).await.unwrap();
         self.writer.write_all(&[LINE_TERM]).await.unwrap();
     }
 
+    fn global(&mut self, name: &str, args: &[&str]) {
+        match name {
+            "RST" => {
+                for (addr, dev) in self.devs.iter_mut() {
+                    *dev = Device::new(*addr);
+                }
+            }
+            "SAV" => self.saved = self.devs.clone(),
+            "RCL" => {
+                for (addr, dev) in self.saved.iter() {
+                    self.devs.insert(*addr, dev.clone());
+                }
+            }
+            _ => {
+                for dev in self.devs.values_mut() {
+                    let _ = dev.execute(name, args);
+                }
+            }
+        }
+    }
+
     fn dev(&mut self, addr: Addr) -> &mut Device {
         self.devs.get_mut(&addr).unwrap()
     }
@@ -73,7 +107,21 @@ impl Emulator {
         let mut rng = Pcg64::seed_from_u64(0xdeadbeef);
         let mut addr = None;
         loop {
-            let cmd = self.recv().await;
+            let msg = self.recv().await;
+            let cmd = if self.framing.checksum {
+                match checksum::strip(&msg) {
+                    Some(cmd) => String::from(cmd),
+                    None => {
+                        // Only selected device responds.
+                        if addr.is_some() {
+                            self.send(&DeviceError::Checksum.code()).await;
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                msg
+            };
             let (name, args) = {
                 let mut parts = cmd.split(' ');
                 (parts.next().unwrap(), parts.collect::<Vec<_>>())
@@ -84,89 +132,27 @@ impl Emulator {
                 continue;
             }
 
-            if name == "ADR" {
+            if let Some(name) = name.strip_prefix('G').filter(|n| GLOBAL.contains(n)) {
+                // Global commands are executed by all devices and aren't answered.
+                self.global(name, &args);
+            } else if name == "ADR" {
                 assert_eq!(args.len(), 1);
-                addr = Some(args[0].parse().unwrap());
-                assert!(self.devs.contains_key(addr.as_ref().unwrap()));
-                sleep(Duration::from_millis(90)).await;
-                self.send("OK").await;
-            } else {
-                let addr = *addr.as_ref().unwrap();
-                match name {
-                    "IDN?" => {
-                        self.send("TDK-Lambda Emulator").await;
-                    }
-                    "SN?" => {
-                        self.send(&format!("Emu-{}", addr)).await;
-                    }
-                    "OUT" => {
-                        self.dev(addr).out = match args[0] {
-                            "0" | "OFF" => false,
-                            "1" | "ON" => true,
-                            _ => panic!(),
-                        };
-                        //self.send("OK").await;
-                        self.send("E07").await;
-                    }
-                    "OUT?" => {
-                        let value = self.dev(addr).out;
-                        let text = if addr == 0 {
-                            if !value {
-                                "OFF"
-                            } else {
-                                "ON"
-                            }
-                        } else if !value {
-                            "0"
-                        } else {
-                            "1"
-                        };
-                        self.send(text).await;
-                    }
-                    "PC" => {
-                        self.dev(addr).current = args[0].parse().unwrap();
-                        self.send("OK").await;
-                    }
-                    "PC?" => {
-                        let value = self.dev(addr).current;
-                        self.send(&value.to_string()).await;
-                    }
-                    "MC?" => {
-                        let value = self.dev(addr).current();
-                        self.send(&value.to_string()).await;
-                    }
-                    "PV" => {
-                        self.dev(addr).voltage = args[0].parse().unwrap();
-                        self.send("OK").await;
-                    }
-                    "PV?" => {
-                        let value = self.dev(addr).voltage;
-                        self.send(&value.to_string()).await;
-                    }
-                    "MV?" => {
-                        let value = self.dev(addr).voltage();
-                        self.send(&value.to_string()).await;
-                    }
-                    "OVP" => {
-                        self.dev(addr).over_voltage = args[0].parse().unwrap();
-                        self.send("OK").await;
-                    }
-                    "OVP?" => {
-                        let value = self.dev(addr).over_voltage;
-                        self.send(&value.to_string()).await;
-                    }
-                    "UVL" => {
-                        self.dev(addr).under_voltage = args[0].parse().unwrap();
-                        self.send("OK").await;
-                    }
-                    "UVL?" => {
-                        let value = self.dev(addr).under_voltage;
-                        self.send(&value.to_string()).await;
-                    }
-                    _ => {
-                        panic!("Unknown command name: {}", name);
-                    }
+                // Missing devices don't respond, but all devices are deselected.
+                addr = args[0].parse().ok().filter(|a| self.devs.contains_key(a));
+                if addr.is_some() {
+                    sleep(Duration::from_millis(90)).await;
+                    self.send("OK").await;
                 }
+            } else {
+                let addr = match addr {
+                    Some(addr) => addr,
+                    None => continue,
+                };
+                let resp = match self.dev(addr).execute(name, &args) {
+                    Ok(resp) => resp,
+                    Err(err) => err.code(),
+                };
+                self.send(&resp).await;
                 if self.dev(addr).alert() && !self.dev(addr).alert {
                     let byte = 0x80 + addr;
                     for _ in 0..2 {
@@ -179,8 +165,11 @@ impl Emulator {
     }
 }
 
+/// Commands which can be sent to all devices with `G` prefix.
+const GLOBAL: &[&str] = &["RST", "PV", "PC", "OUT", "SAV", "RCL"];
+
+#[derive(Clone)]
 struct Device {
-    #[allow(dead_code)]
     addr: Addr,
     alert: bool,
     out: bool,
@@ -188,6 +177,8 @@ struct Device {
     current: f64,
     over_voltage: f64,
     under_voltage: f64,
+    /// Load resistance in ohms.
+    load: f64,
 }
 
 impl Device {
@@ -200,27 +191,141 @@ impl Device {
             current: 0.0,
             over_voltage: 10.0,
             under_voltage: 0.0,
+            load: 1.0 + f64::from(addr),
         }
     }
 
-    fn voltage(&self) -> f64 {
-        if self.out {
-            self.voltage.clamp(self.under_voltage, self.over_voltage)
+    /// Supply switches to constant current when load draws more than current setting.
+    fn mode(&self) -> &'static str {
+        if !self.out {
+            "OFF"
+        } else if self.voltage <= self.current * self.load {
+            "CV"
         } else {
-            0.0
+            "CC"
         }
     }
-    fn current(&self) -> f64 {
-        if self.out {
-            self.current
-        } else {
-            0.0
+
+    fn voltage(&self) -> f64 {
+        match self.mode() {
+            "CV" => self.voltage.clamp(self.under_voltage, self.over_voltage),
+            "CC" => self.current * self.load,
+            _ => 0.0,
         }
     }
+    fn current(&self) -> f64 {
+        self.voltage() / self.load
+    }
 
     fn alert(&self) -> bool {
         !(self.under_voltage..self.over_voltage).contains(&self.voltage)
     }
+
+    /// Fault register, over-voltage is the only emulated fault.
+    fn fault(&self) -> u8 {
+        if self.alert() {
+            1 << 4
+        } else {
+            0
+        }
+    }
+
+    /// Status register: mode, fault summary and remote control.
+    fn status(&self) -> u8 {
+        let mode = match self.mode() {
+            "CV" => 1 << 0,
+            "CC" => 1 << 1,
+            _ => 0,
+        };
+        let fault = if self.fault() == 0 { 1 << 2 } else { 1 << 3 };
+        mode | fault
+    }
+
+    fn execute(&mut self, name: &str, args: &[&str]) -> Result<String, DeviceError> {
+        let arg = || -> Result<f64, DeviceError> {
+            args.first()
+                .ok_or(DeviceError::MissingParameter)?
+                .parse()
+                .map_err(|_| DeviceError::IllegalParameter)
+        };
+        let ok = || Ok(String::from("OK"));
+        match name {
+            // Device 0 emulates old firmware.
+            "IDN?" => Ok(String::from(if self.addr == 0 {
+                "LAMBDA,GEN-EMU"
+            } else {
+                "TDK-LAMBDA,GEN-EMU"
+            })),
+            "SN?" => Ok(format!("Emu-{}", self.addr)),
+            "REV?" => Ok(String::from("Emu-1.0")),
+            "OUT" => {
+                let value = match *args.first().ok_or(DeviceError::MissingParameter)? {
+                    "0" | "OFF" => false,
+                    "1" | "ON" => true,
+                    _ => return Err(DeviceError::IllegalParameter),
+                };
+                if value && self.alert {
+                    return Err(DeviceError::OutputOnFault);
+                }
+                self.out = value;
+                ok()
+            }
+            "OUT?" => Ok(String::from(match (self.addr == 0, self.out) {
+                (true, false) => "OFF",
+                (true, true) => "ON",
+                (false, false) => "0",
+                (false, true) => "1",
+            })),
+            "PC" => {
+                self.current = arg()?;
+                ok()
+            }
+            "PC?" => Ok(self.current.to_string()),
+            "MC?" => Ok(self.current().to_string()),
+            "PV" => {
+                let value = arg()?;
+                if value > self.over_voltage {
+                    return Err(DeviceError::VoltAboveRange);
+                }
+                if value < self.under_voltage {
+                    return Err(DeviceError::VoltBelowUvl);
+                }
+                self.voltage = value;
+                ok()
+            }
+            "PV?" => Ok(self.voltage.to_string()),
+            "MV?" => Ok(self.voltage().to_string()),
+            "MODE?" => Ok(String::from(self.mode())),
+            "STT?" => Ok(format!(
+                "MV({}),PV({}),MC({}),PC({}),SR({:02X}),FR({:02X})",
+                self.voltage(),
+                self.voltage,
+                self.current(),
+                self.current,
+                self.status(),
+                self.fault()
+            )),
+            "OVP" => {
+                let value = arg()?;
+                if value < self.voltage {
+                    return Err(DeviceError::OvpBelowVolt);
+                }
+                self.over_voltage = value;
+                ok()
+            }
+            "OVP?" => Ok(self.over_voltage.to_string()),
+            "UVL" => {
+                let value = arg()?;
+                if value > self.voltage {
+                    return Err(DeviceError::UvlAboveVolt);
+                }
+                self.under_voltage = value;
+                ok()
+            }
+            "UVL?" => Ok(self.under_voltage.to_string()),
+            _ => Err(DeviceError::IllegalCommand),
+        }
+    }
 }
 
 #[pin_project]
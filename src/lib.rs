@@ -1,73 +1,156 @@
 #![forbid(unsafe_code)]
 
-#[cfg(not(any(feature = "tcp", feature = "serial", feature = "emulator")))]
-compile_error!("You need to enable either 'tcp',  'serial' or 'emulator' feature.");
-#[cfg(all(feature = "tcp", feature = "serial", feature = "emulator"))]
-compile_error!("Features 'tcp', 'serial' and 'emulator' cannot be enabled both at once.");
-
+mod bus;
+mod config;
 mod device;
-#[cfg(feature = "emulator")]
 mod emulator;
 mod serial;
+mod transport;
 
 /// *Export symbols being called from IOC.*
 pub use ferrite::export;
 
 use ferrite::{entry_point, Context};
-use futures::executor::block_on;
+use futures::future;
 use macro_rules_attribute::apply;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
 use tokio::runtime;
 
 use crate::{
-    device::{DeviceNew, DeviceOld},
+    bus::{Bus, Devices},
+    config::Config,
     serial::Multiplexer,
+    transport::Factory,
 };
 
+#[derive(Error, Debug)]
+enum Error {
+    #[error("{0}")]
+    Config(#[from] config::Error),
+}
+
 #[apply(entry_point)]
 fn app_main(mut ctx: Context) {
     use env_logger::Env;
     env_logger::Builder::from_env(Env::default().default_filter_or("trace")).init();
-    block_on(async_main(ctx));
-}
-
-async fn async_main(mut ctx: Context) -> ! {
-    log::info!("start");
     let rt = runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap();
-    let _guard = rt.enter();
-    let addrs_old = [0];
-    let addrs_new = 1..7;
+    // Discovery at startup needs runtime drivers, so everything is run on the runtime.
+    if let Err(err) = rt.block_on(async_main(ctx)) {
+        log::error!("{}", err);
+    }
+}
 
-    #[cfg(feature = "tcp")]
-    let port = tokio::net::TcpStream::connect("10.0.0.77:4001")
-        .await
-        .unwrap();
+async fn async_main(ctx: Context) -> Result<(), Error> {
+    log::info!("start");
+    let config = Config::from_env()?;
+    let rt = runtime::Handle::current();
 
-    #[cfg(feature = "emulator")]
-    let port = {
-        let (emu, port) =
-            emulator::Emulator::new(addrs_old.into_iter().chain(addrs_new.clone().into_iter()));
-        rt.spawn(emu.run());
-        port
-    };
+    // Records left after startup are used by devices added later.
+    let epics = Arc::new(Mutex::new(ctx));
+    for bus in &config.buses {
+        let framing = serial::Framing::from(&bus.framing);
+        let transport = Factory::new(
+            bus.transport.clone(),
+            framing.clone(),
+            bus.devices.iter().map(|dev| dev.addr),
+        );
+        let mut mux = Multiplexer::new(
+            &bus.name,
+            transport,
+            bus.protocol.build(&bus.framing),
+            (&bus.timing).into(),
+            framing,
+            (&bus.schedule).into(),
+        );
+        let configured = if bus.discover {
+            discover(&mut mux, bus, &epics).await
+        } else {
+            bus.devices.clone()
+        };
+        if configured.is_empty() {
+            log::warn!("Bus '{}': No devices to serve", bus.name);
+        }
+        let mut devices = Devices::new(bus.clone(), mux.control(), epics.clone());
+        for dev in &configured {
+            if let Err(err) = devices.add(dev) {
+                log::error!(
+                    "Bus '{}': Cannot add device {}: {}",
+                    bus.name,
+                    dev.addr,
+                    err
+                );
+            }
+        }
+        let prefix = bus.records_prefix();
+        let mut ctx = epics.lock().unwrap();
+        if bus::has_records(&ctx, &prefix) {
+            let (stats, bcast) = (mux.stats(), mux.broadcaster());
+            rt.spawn(Bus::new(&prefix, &mut ctx, stats, bcast, devices).run());
+        } else {
+            log::info!(
+                "Bus '{}': No bus records with prefix '{}'",
+                bus.name,
+                prefix
+            );
+        }
+        rt.spawn(mux.run());
+    }
 
-    #[cfg(feature = "serial")]
-    let port = {
-        use tokio_serial::SerialPortBuilderExt;
-        tokio_serial::new("/dev/ttyUSB0", 19200)
-            .open_native_async()
-            .unwrap()
-    };
+    // All work is done in spawned tasks.
+    future::pending().await
+}
 
-    let mut mux = Multiplexer::new(port);
-    for addr in addrs_old {
-        rt.spawn(DeviceOld::new(addr, &mut ctx, mux.add_client(addr).unwrap()).run());
+/// Discover devices on the bus and reconcile them with configured ones.
+///
+/// Configured devices are kept even if missing, unknown devices are added if they have EPICS records.
+async fn discover<T: serial::Transport>(
+    mux: &mut Multiplexer<T>,
+    bus: &config::Bus,
+    epics: &Mutex<Context>,
+) -> Vec<config::Device> {
+    log::info!("Bus '{}': Discover devices", bus.name);
+    let found = match mux.discover(0..=config::MAX_ADDR).await {
+        Ok(found) => found,
+        Err(err) => {
+            log::error!(
+                "Bus '{}': Cannot discover devices: {}, serve configured ones",
+                bus.name,
+                err
+            );
+            return bus.devices.clone();
+        }
+    };
+    let mut devices = bus.devices.clone();
+    for dev in &bus.devices {
+        if !found.iter().any(|info| info.addr == dev.addr) {
+            log::warn!(
+                "Bus '{}': Configured device {} is missing",
+                bus.name,
+                dev.addr
+            );
+        }
     }
-    for addr in addrs_new {
-        rt.spawn(DeviceNew::new(addr, &mut ctx, mux.add_client(addr).unwrap()).run());
+    for info in found {
+        if bus.devices.iter().any(|dev| dev.addr == info.addr) {
+            continue;
+        }
+        let dev = config::Device::new(info.addr);
+        let prefix = bus.device_prefix(&dev);
+        if device::has_records(&epics.lock().unwrap(), &prefix) {
+            log::warn!("Bus '{}': Unknown device {} found", bus.name, info.addr);
+            devices.push(dev);
+        } else {
+            log::warn!(
+                "Bus '{}': Unknown device {} found, but there are no records with prefix '{}'",
+                bus.name,
+                info.addr,
+                prefix
+            );
+        }
     }
-    assert!(ctx.registry.is_empty());
-    rt.block_on(mux.run())
+    devices
 }
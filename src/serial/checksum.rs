@@ -0,0 +1,48 @@
+//! Optional message checksum: `$` followed by two hex digits of sum of message bytes modulo 256.
+
+const SEP: char = '$';
+
+fn sum(msg: &str) -> u8 {
+    msg.bytes().fold(0, u8::wrapping_add)
+}
+
+/// Append checksum to message.
+pub fn append(msg: &str) -> String {
+    format!("{}{}{:02X}", msg, SEP, sum(msg))
+}
+
+/// Verify and remove checksum, returns `None` if it's missing or wrong.
+pub fn strip(msg: &str) -> Option<&str> {
+    let (body, text) = msg.rsplit_once(SEP)?;
+    if text.len() == 2 && u8::from_str_radix(text, 16).ok()? == sum(body) {
+        Some(body)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_checksum() {
+        assert_eq!(append("ADR 1"), "ADR 1$28");
+        assert_eq!(append("OK"), "OK$9A");
+    }
+
+    #[test]
+    fn round_trip() {
+        for msg in ["ADR 1", "PV 12.5", "MV(45.201),SR(30)", ""] {
+            assert_eq!(strip(&append(msg)), Some(msg));
+        }
+    }
+
+    #[test]
+    fn reject_bad_checksum() {
+        assert_eq!(strip("OK$9B"), None);
+        assert_eq!(strip("OK$9"), None);
+        assert_eq!(strip("OK$XY"), None);
+        assert_eq!(strip("OK"), None);
+    }
+}
@@ -0,0 +1,154 @@
+use thiserror::Error;
+
+/// Error code sent by device in response to command.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    #[error("C01: Illegal command or query")]
+    IllegalCommand,
+    #[error("C02: Missing parameter")]
+    MissingParameter,
+    #[error("C03: Illegal parameter")]
+    IllegalParameter,
+    #[error("C04: Checksum error")]
+    Checksum,
+    #[error("C05: Setting out of range")]
+    OutOfRange,
+    #[error("C{0:02}: Unknown command error")]
+    OtherCommand(u8),
+
+    #[error("E01: PV above acceptable range")]
+    VoltAboveRange,
+    #[error("E02: PV below UVL")]
+    VoltBelowUvl,
+    #[error("E04: OVP below PV")]
+    OvpBelowVolt,
+    #[error("E06: UVL above PV")]
+    UvlAboveVolt,
+    #[error("E07: Output on during fault")]
+    OutputOnFault,
+    #[error("E{0:02}: Unknown execution error")]
+    OtherExecution(u8),
+}
+
+impl DeviceError {
+    /// Parse response, returns `None` if it isn't an error code.
+    pub fn parse(resp: &str) -> Option<Self> {
+        let bytes = resp.as_bytes();
+        if bytes.len() != 3 || !bytes[1..].iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        let num = resp[1..].parse::<u8>().unwrap();
+        Some(match (bytes[0], num) {
+            (b'C', 1) => Self::IllegalCommand,
+            (b'C', 2) => Self::MissingParameter,
+            (b'C', 3) => Self::IllegalParameter,
+            (b'C', 4) => Self::Checksum,
+            (b'C', 5) => Self::OutOfRange,
+            (b'C', n) => Self::OtherCommand(n),
+            (b'E', 1) => Self::VoltAboveRange,
+            (b'E', 2) => Self::VoltBelowUvl,
+            (b'E', 4) => Self::OvpBelowVolt,
+            (b'E', 6) => Self::UvlAboveVolt,
+            (b'E', 7) => Self::OutputOnFault,
+            (b'E', n) => Self::OtherExecution(n),
+            _ => return None,
+        })
+    }
+
+    /// Code as sent by device.
+    pub fn code(&self) -> String {
+        let (kind, num) = match *self {
+            Self::IllegalCommand => ('C', 1),
+            Self::MissingParameter => ('C', 2),
+            Self::IllegalParameter => ('C', 3),
+            Self::Checksum => ('C', 4),
+            Self::OutOfRange => ('C', 5),
+            Self::OtherCommand(n) => ('C', n),
+            Self::VoltAboveRange => ('E', 1),
+            Self::VoltBelowUvl => ('E', 2),
+            Self::OvpBelowVolt => ('E', 4),
+            Self::UvlAboveVolt => ('E', 6),
+            Self::OutputOnFault => ('E', 7),
+            Self::OtherExecution(n) => ('E', n),
+        };
+        format!("{}{:02}", kind, num)
+    }
+}
+
+/// Entry of SCPI error queue read by `SYST:ERR?`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{code}: {message}")]
+pub struct ScpiError {
+    /// Error code, `0` means no error.
+    pub code: i32,
+    pub message: String,
+}
+
+impl ScpiError {
+    /// Parse response like `-222,"Data out of range"`, returns `None` if it has different format.
+    pub fn parse(resp: &str) -> Option<Self> {
+        let (code, message) = resp.split_once(',')?;
+        Some(Self {
+            code: code.trim().parse().ok()?,
+            message: String::from(message.trim().trim_matches('"')),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODES: &[(&str, DeviceError)] = &[
+        ("C01", DeviceError::IllegalCommand),
+        ("C02", DeviceError::MissingParameter),
+        ("C03", DeviceError::IllegalParameter),
+        ("C04", DeviceError::Checksum),
+        ("C05", DeviceError::OutOfRange),
+        ("C09", DeviceError::OtherCommand(9)),
+        ("E01", DeviceError::VoltAboveRange),
+        ("E02", DeviceError::VoltBelowUvl),
+        ("E04", DeviceError::OvpBelowVolt),
+        ("E06", DeviceError::UvlAboveVolt),
+        ("E07", DeviceError::OutputOnFault),
+        ("E03", DeviceError::OtherExecution(3)),
+        ("E99", DeviceError::OtherExecution(99)),
+    ];
+
+    #[test]
+    fn parse_codes() {
+        for (code, err) in CODES {
+            assert_eq!(DeviceError::parse(code), Some(*err), "{}", code);
+        }
+    }
+
+    #[test]
+    fn code_round_trip() {
+        for (code, err) in CODES {
+            assert_eq!(err.code(), *code);
+            assert_eq!(DeviceError::parse(&err.code()), Some(*err));
+        }
+    }
+
+    #[test]
+    fn parse_non_codes() {
+        for resp in ["OK", "12.5", "E1", "X01", "C1A", "E001", "", "ON"] {
+            assert_eq!(DeviceError::parse(resp), None, "{}", resp);
+        }
+    }
+
+    #[test]
+    fn parse_scpi_errors() {
+        assert_eq!(
+            ScpiError::parse("-222,\"Data out of range\""),
+            Some(ScpiError {
+                code: -222,
+                message: String::from("Data out of range"),
+            })
+        );
+        assert_eq!(ScpiError::parse("0,\"No error\"").map(|e| e.code), Some(0));
+        for resp in ["garbage", "x,\"y\"", "", ",\"No error\""] {
+            assert_eq!(ScpiError::parse(resp), None, "{}", resp);
+        }
+    }
+}
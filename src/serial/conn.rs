@@ -2,79 +2,260 @@ use pin_project::pin_project;
 use std::{
     io,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
-    time::Duration,
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
     sync::mpsc::UnboundedSender as Sender,
     time::{sleep, timeout},
 };
 
-use super::{Addr, Error, LINE_TERM};
+use super::{
+    stats::{self, Stats},
+    Addr, DeviceError, Error, Framing, Protocol, Timing,
+};
 
-fn byte_is_intr(b: u8) -> Option<Addr> {
-    if b >= 0x80 {
-        Some(b - 0x80)
-    } else {
-        None
-    }
+/// Maximal number of bytes discarded at once, to not stall on endless garbage.
+const MAX_DRAIN: usize = 4096;
+
+fn closed_error() -> Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "Serial connection closed unexpectedly",
+    )
+    .into()
 }
 
 pub struct Connection<W: AsyncWrite + Unpin, R: AsyncRead + Unpin> {
     writer: W,
     reader: BufReader<FilterReader<R>>,
-    delay: Duration,
-    timeout: Duration,
-    retries: usize,
+    protocol: Arc<dyn Protocol>,
+    timing: Timing,
+    framing: Framing,
+    stats: Arc<Stats>,
+    /// Currently selected device address.
+    active: Option<Addr>,
+    /// Input may contain stale data after failed command.
+    dirty: bool,
 }
 
 impl<W: AsyncWrite + Unpin, R: AsyncRead + Unpin> Connection<W, R> {
-    pub fn new((reader, writer): (R, W), intr: Sender<Addr>) -> Self {
+    pub fn new(
+        (reader, writer): (R, W),
+        intr: Sender<Addr>,
+        protocol: Arc<dyn Protocol>,
+        timing: Timing,
+        framing: Framing,
+        stats: Arc<Stats>,
+    ) -> Self {
         Self {
             writer,
-            reader: BufReader::new(FilterReader::new(reader, intr)),
-            delay: Duration::from_millis(10),
-            timeout: Duration::from_millis(200),
-            retries: 2,
+            reader: BufReader::new(FilterReader::new(reader, intr, protocol.clone())),
+            protocol,
+            timing,
+            framing,
+            stats,
+            active: None,
+            dirty: false,
         }
     }
 
+    /// Discard input until there is no data during response timeout.
+    async fn drain(&mut self) -> Result<(), Error> {
+        let mut buf = [0; 64];
+        let mut count = 0;
+        while count < MAX_DRAIN {
+            match timeout(self.timing.timeout, self.reader.read(&mut buf)).await {
+                Ok(res) => match res? {
+                    0 => return Err(closed_error()),
+                    n => count += n,
+                },
+                Err(_) => break,
+            }
+        }
+        if count > 0 {
+            log::warn!("Discarded {} stale bytes", count);
+            stats::inc(&self.stats.resyncs);
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Select device to communicate with, if it isn't selected yet.
+    ///
+    /// Returns `false` if device is already selected and nothing is sent.
+    pub async fn select(&mut self, addr: Addr) -> Result<bool, Error> {
+        if self.active == Some(addr) {
+            return Ok(false);
+        }
+        // Selection of another device deselects the current one even on failure.
+        self.active = None;
+        stats::inc(&self.stats.adr_switches);
+        let resp = self.request(&self.protocol.select(addr)).await?;
+        if self.protocol.is_ack(&resp) {
+            self.active.replace(addr);
+            Ok(true)
+        } else {
+            Err(Error::Unexpected(resp))
+        }
+    }
+
+    /// Wait before sending command, resynchronize if needed.
+    async fn prepare(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            self.drain().await
+        } else {
+            sleep(self.timing.delay).await;
+            Ok(())
+        }
+    }
+
+    /// Write message and check its echo if needed.
+    async fn send(&mut self, msg: &str) -> Result<(), Error> {
+        let frame = msg.as_bytes();
+        self.writer.write_all(frame).await?;
+        self.writer.flush().await?;
+        log::trace!("-> '{}'", msg.trim_end());
+
+        if self.framing.echo {
+            let mut echo = vec![0; frame.len()];
+            self.reader.read_exact(&mut echo).await?;
+            if echo != frame {
+                return Err(Error::Collision(echo));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send command to all devices at once, there is no response to it.
+    pub async fn broadcast(&mut self, cmd: &str) -> Result<(), Error> {
+        self.transmit(cmd).await
+    }
+
+    /// Send command without response.
+    async fn transmit(&mut self, cmd: &str) -> Result<(), Error> {
+        let msg = self.protocol.encode(cmd);
+        self.prepare().await?;
+        stats::inc(&self.stats.requests);
+        match timeout(self.timing.timeout, self.send(&msg)).await {
+            Ok(Err(Error::Collision(echo))) => {
+                stats::inc(&self.stats.collisions);
+                self.dirty = true;
+                Err(Error::Collision(echo))
+            }
+            Ok(res) => res,
+            Err(_) => Err(Error::Timeout),
+        }
+    }
+
+    /// Send command and read response to it, which is empty if the command has no response.
+    ///
+    /// Error of command without response is read from device error queue if there is one.
     pub async fn request(&mut self, cmd: &str) -> Result<String, Error> {
-        for i in 0..self.retries {
-            sleep(self.delay).await;
+        if self.protocol.has_response(cmd) {
+            return self.exchange(cmd).await;
+        }
+        self.transmit(cmd).await?;
+        if let Some(query) = self.protocol.error_query() {
+            let resp = self.exchange(&query).await?;
+            self.protocol.queued_error(&resp)?;
+        }
+        Ok(String::new())
+    }
+
+    /// Send command and read response to it, retrying on failure.
+    async fn exchange(&mut self, cmd: &str) -> Result<String, Error> {
+        let msg = self.protocol.encode(cmd);
+        let term = self.protocol.terminator();
+        let mut error = Error::Timeout;
+        for i in 0..self.timing.retries {
+            self.prepare().await?;
+            // Failed attempts leave connection dirty.
+            self.dirty = true;
+            stats::inc(&self.stats.requests);
 
             let mut buf = Vec::new();
-            match timeout(self.timeout, async {
-                self.writer.write_all(cmd.as_bytes()).await?;
-                self.writer.write_u8(LINE_TERM).await?;
-                self.writer.flush().await?;
-                log::trace!("-> '{}'", cmd);
+            match timeout(self.timing.timeout, async {
+                self.send(&msg).await?;
 
                 buf.clear();
-                self.reader.read_until(LINE_TERM, &mut buf).await?;
-                if buf.pop().map(|b| b != LINE_TERM).unwrap_or(true) {
-                    return Err(io::Error::new(
-                        io::ErrorKind::BrokenPipe,
-                        "Serial connection closed unexpectedly",
-                    ));
+                self.reader.read_until(term, &mut buf).await?;
+                if buf.pop().map(|b| b != term).unwrap_or(true) {
+                    return Err(closed_error());
                 }
                 Ok(())
             })
             .await
             {
+                Ok(Err(Error::Collision(echo))) => {
+                    log::warn!(
+                        "Bus collision: '{}' echoed as '{}' (attempt: {})",
+                        cmd,
+                        String::from_utf8_lossy(&echo),
+                        i + 1
+                    );
+                    stats::inc(&self.stats.collisions);
+                    error = Error::Collision(echo);
+                }
                 Ok(io_res) => {
                     io_res?;
-                    let resp = String::from_utf8(buf)?;
-                    log::trace!("<- '{}'", resp);
-                    return Ok(resp);
+                    let frame = String::from_utf8(buf)?;
+                    log::trace!("<- '{}'", frame);
+                    let resp = match self.protocol.decode(&frame) {
+                        Some(body) => String::from(body),
+                        None => {
+                            log::warn!(
+                                "Bad checksum of response '{}' to '{}' (attempt: {})",
+                                frame,
+                                cmd,
+                                i + 1
+                            );
+                            stats::inc(&self.stats.checksum_errors);
+                            error = Error::Checksum;
+                            continue;
+                        }
+                    };
+                    match self.protocol.error(&resp) {
+                        // Command was corrupted on its way to device.
+                        Some(DeviceError::Checksum) => {
+                            log::warn!(
+                                "Device reports bad checksum of '{}' (attempt: {})",
+                                msg.trim_end(),
+                                i + 1
+                            );
+                            stats::inc(&self.stats.checksum_errors);
+                            error = Error::Device(DeviceError::Checksum);
+                        }
+                        Some(err) => {
+                            self.dirty = false;
+                            return Err(Error::Device(err));
+                        }
+                        // Probably late response to previous command.
+                        None if !self.protocol.is_expected(cmd, &resp) => {
+                            log::warn!(
+                                "Response '{}' doesn't match '{}' (attempt: {})",
+                                resp,
+                                cmd,
+                                i + 1
+                            );
+                            stats::inc(&self.stats.mismatches);
+                            error = Error::Mismatch(resp);
+                        }
+                        None => {
+                            self.dirty = false;
+                            return Ok(resp);
+                        }
+                    }
                 }
                 Err(_) => {
                     log::warn!("No response to '{}' (attempt: {})", cmd, i + 1);
+                    stats::inc(&self.stats.timeouts);
+                    error = Error::Timeout;
                 }
             }
         }
-        Err(Error::Timeout)
+        Err(error)
     }
 }
 
@@ -84,14 +265,16 @@ struct FilterReader<R: AsyncRead + Unpin> {
     reader: R,
     prev: Option<Addr>,
     chan: Sender<Addr>,
+    protocol: Arc<dyn Protocol>,
 }
 
 impl<R: AsyncRead + Unpin> FilterReader<R> {
-    pub fn new(reader: R, intr_chan: Sender<Addr>) -> Self {
+    pub fn new(reader: R, intr_chan: Sender<Addr>, protocol: Arc<dyn Protocol>) -> Self {
         Self {
             reader,
             prev: None,
             chan: intr_chan,
+            protocol,
         }
     }
 }
@@ -114,7 +297,7 @@ impl<R: AsyncRead + Unpin> AsyncRead for FilterReader<R> {
                 let mut j = 0;
                 for i in 0..s.len() {
                     let b = s[i];
-                    match (this.prev.take(), byte_is_intr(b)) {
+                    match (this.prev.take(), this.protocol.interrupt(b)) {
                         (Some(p), Some(a)) => {
                             if a == p {
                                 this.chan.send(a).unwrap();
@@ -145,3 +328,126 @@ impl<R: AsyncRead + Unpin> AsyncRead for FilterReader<R> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    };
+    use tokio::{
+        io::{duplex, split, DuplexStream, ReadHalf, WriteHalf},
+        runtime,
+        sync::mpsc::unbounded_channel,
+    };
+
+    use crate::serial::{Genesys, LINE_TERM};
+
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    type Conn = Connection<WriteHalf<DuplexStream>, ReadHalf<DuplexStream>>;
+
+    /// Device which sends `stale` bytes first, then answers each received line after delay.
+    fn device(port: DuplexStream, stale: &'static str, answer: fn(&str) -> (Duration, String)) {
+        runtime::Handle::current().spawn(async move {
+            let (reader, mut writer) = split(port);
+            writer.write_all(stale.as_bytes()).await.unwrap();
+            let mut reader = BufReader::new(reader);
+            let mut buf = Vec::new();
+            while reader.read_until(LINE_TERM, &mut buf).await.unwrap() > 0 {
+                let (delay, resp) = answer(&String::from_utf8(buf.split_off(0)).unwrap());
+                sleep(delay).await;
+                writer.write_all(resp.as_bytes()).await.unwrap();
+            }
+        });
+    }
+
+    /// Answer `OK` at once.
+    fn ok(_line: &str) -> (Duration, String) {
+        (Duration::ZERO, String::from("OK\r"))
+    }
+
+    fn connection(port: DuplexStream, retries: usize, echo: bool) -> (Conn, Arc<Stats>) {
+        let stats = Arc::new(Stats::default());
+        let (intr, _) = unbounded_channel();
+        let conn = Connection::new(
+            split(port),
+            intr,
+            Arc::new(Genesys::default()),
+            Timing {
+                delay: Duration::ZERO,
+                timeout: TIMEOUT,
+                retries,
+                expiry: Duration::from_secs(1),
+            },
+            Framing {
+                checksum: false,
+                echo,
+            },
+            stats.clone(),
+        );
+        (conn, stats)
+    }
+
+    fn load(value: &AtomicU64) -> u64 {
+        value.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn mismatched_reply_retried() {
+        let (port, dev_port) = duplex(256);
+        // Reply to previous query is still in input.
+        device(dev_port, "1.5\r", ok);
+        let (mut conn, stats) = connection(port, 2, false);
+
+        assert_eq!(conn.request("OUT 1").await.unwrap(), "OK");
+        assert_eq!(load(&stats.mismatches), 1);
+        // `OK` to the first attempt is discarded before the second one.
+        assert_eq!(load(&stats.resyncs), 1);
+        assert_eq!(load(&stats.requests), 2);
+    }
+
+    #[tokio::test]
+    async fn late_reply_discarded() {
+        let (port, dev_port) = duplex(256);
+        device(dev_port, "", |line| match line {
+            "MV?\r" => (TIMEOUT + TIMEOUT / 2, String::from("1.5\r")),
+            _ => ok(line),
+        });
+        let (mut conn, stats) = connection(port, 1, false);
+
+        assert!(matches!(conn.request("MV?").await, Err(Error::Timeout)));
+        assert_eq!(load(&stats.timeouts), 1);
+        assert_eq!(conn.request("OUT 1").await.unwrap(), "OK");
+        assert_eq!(load(&stats.resyncs), 1);
+        assert_eq!(load(&stats.mismatches), 0);
+    }
+
+    #[tokio::test]
+    async fn echo_skipped() {
+        let (port, dev_port) = duplex(256);
+        device(dev_port, "", |line| {
+            (Duration::ZERO, format!("{}OK\r", line))
+        });
+        let (mut conn, stats) = connection(port, 1, true);
+
+        assert_eq!(conn.request("OUT 1").await.unwrap(), "OK");
+        assert_eq!(load(&stats.collisions), 0);
+    }
+
+    #[tokio::test]
+    async fn corrupted_echo_is_collision() {
+        let (port, dev_port) = duplex(256);
+        device(dev_port, "", |line| {
+            (Duration::ZERO, format!("{}OK\r", line.replace('1', "3")))
+        });
+        let (mut conn, stats) = connection(port, 1, true);
+
+        match conn.request("OUT 1").await {
+            Err(Error::Collision(echo)) => assert_eq!(echo, b"OUT 3\r"),
+            res => panic!("Collision expected, got {:?}", res),
+        }
+        assert_eq!(load(&stats.collisions), 1);
+    }
+}
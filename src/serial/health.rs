@@ -0,0 +1,101 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Number of consecutive failures after which device is considered offline.
+const MAX_FAILURES: usize = 3;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Health of device tracked by multiplexer.
+#[derive(Debug, Default)]
+pub struct Health {
+    /// Number of consecutive failures.
+    failures: usize,
+    /// Current backoff and time of next probe, if device is offline.
+    probe: Option<(Duration, Instant)>,
+}
+
+impl Health {
+    pub fn is_online(&self) -> bool {
+        self.probe.is_none()
+    }
+
+    /// Time to probe offline device.
+    pub fn next_probe(&self) -> Option<Instant> {
+        self.probe.map(|(_, time)| time)
+    }
+
+    /// Record result of communication, returns `true` if online state has changed.
+    pub fn report(&mut self, responded: bool) -> bool {
+        if responded {
+            self.failures = 0;
+            return self.probe.take().is_some();
+        }
+        self.failures += 1;
+        match &mut self.probe {
+            None if self.failures >= MAX_FAILURES => {
+                self.probe = Some((MIN_BACKOFF, Instant::now() + MIN_BACKOFF));
+                true
+            }
+            None => false,
+            Some((backoff, time)) => {
+                *backoff = (2 * *backoff).min(MAX_BACKOFF);
+                *time = Instant::now() + *backoff;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(health: &Health) -> Option<Duration> {
+        health.probe.map(|(backoff, _)| backoff)
+    }
+
+    #[test]
+    fn offline_after_failures() {
+        let mut health = Health::default();
+        for _ in 1..MAX_FAILURES {
+            assert!(!health.report(false));
+            assert!(health.is_online());
+        }
+        assert!(health.report(false));
+        assert!(!health.is_online());
+        assert_eq!(backoff(&health), Some(MIN_BACKOFF));
+        assert!(health.next_probe().unwrap() > Instant::now());
+    }
+
+    #[test]
+    fn response_resets_failures() {
+        let mut health = Health::default();
+        for _ in 1..MAX_FAILURES {
+            health.report(false);
+        }
+        assert!(!health.report(true));
+        assert!(!health.report(false));
+        assert!(health.is_online());
+    }
+
+    #[test]
+    fn backoff_grows_up_to_limit() {
+        let mut health = Health::default();
+        for _ in 0..MAX_FAILURES {
+            health.report(false);
+        }
+        let mut expected = MIN_BACKOFF;
+        for _ in 0..10 {
+            assert!(!health.report(false));
+            expected = (2 * expected).min(MAX_BACKOFF);
+            assert_eq!(backoff(&health), Some(expected));
+        }
+        assert_eq!(backoff(&health), Some(MAX_BACKOFF));
+
+        assert!(health.report(true));
+        assert!(health.is_online());
+        assert_eq!(health.next_probe(), None);
+    }
+}
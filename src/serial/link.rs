@@ -0,0 +1,144 @@
+use futures::future::BoxFuture;
+use std::{io, sync::Arc, time::Duration};
+use tokio::{
+    io::{split, AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    sync::mpsc::UnboundedSender as Sender,
+    time::{sleep, timeout},
+};
+
+use super::{Addr, Connection, Error, Framing, Protocol, Stats, Timing};
+
+/// Source of ports to communicate with devices over.
+pub trait Transport: Send + 'static {
+    type Port: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    /// Open new port.
+    fn open(&self) -> BoxFuture<'_, io::Result<Self::Port>>;
+}
+
+type PortConnection<P> = Connection<WriteHalf<P>, ReadHalf<P>>;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Time to wait for port to open, unreachable host may not refuse connection at all.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection which is reopened after transport loss.
+pub struct Link<T: Transport> {
+    name: String,
+    transport: T,
+    protocol: Arc<dyn Protocol>,
+    timing: Timing,
+    framing: Framing,
+    intr: Sender<Addr>,
+    stats: Arc<Stats>,
+    conn: Option<PortConnection<T::Port>>,
+}
+
+impl<T: Transport> Link<T> {
+    pub fn new(
+        name: &str,
+        transport: T,
+        protocol: Arc<dyn Protocol>,
+        timing: Timing,
+        framing: Framing,
+        intr: Sender<Addr>,
+        stats: Arc<Stats>,
+    ) -> Self {
+        Self {
+            name: String::from(name),
+            transport,
+            protocol,
+            timing,
+            framing,
+            intr,
+            stats,
+            conn: None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// Open connection retrying with exponential backoff until success.
+    pub async fn connect(&mut self) {
+        self.try_connect(usize::MAX).await;
+    }
+
+    /// Open connection making at most given number of attempts, returns whether it's opened.
+    pub async fn try_connect(&mut self, attempts: usize) -> bool {
+        let mut backoff = MIN_BACKOFF;
+        for attempt in 1..=attempts {
+            let res = match timeout(CONNECT_TIMEOUT, self.transport.open()).await {
+                Ok(res) => res,
+                Err(_) => Err(io::ErrorKind::TimedOut.into()),
+            };
+            match res {
+                Ok(port) => {
+                    log::info!("{}: Connected", self.name);
+                    self.conn.replace(Connection::new(
+                        split(port),
+                        self.intr.clone(),
+                        self.protocol.clone(),
+                        self.timing.clone(),
+                        self.framing.clone(),
+                        self.stats.clone(),
+                    ));
+                    return true;
+                }
+                Err(err) if attempt < attempts => {
+                    log::error!(
+                        "{}: Cannot connect: {}, retry in {:?}",
+                        self.name,
+                        err,
+                        backoff
+                    );
+                    sleep(backoff).await;
+                    backoff = (2 * backoff).min(MAX_BACKOFF);
+                }
+                Err(err) => log::error!("{}: Cannot connect: {}", self.name, err),
+            }
+        }
+        false
+    }
+
+    /// Drop connection on I/O error.
+    fn check<R>(&mut self, res: Result<R, Error>) -> Result<R, Error> {
+        if let Err(Error::Io(err)) = &res {
+            log::error!("{}: Connection lost: {}", self.name, err);
+            self.conn = None;
+        }
+        res
+    }
+
+    pub async fn select(&mut self, addr: Addr) -> Result<bool, Error> {
+        let res = self
+            .conn
+            .as_mut()
+            .ok_or(Error::Disconnected)?
+            .select(addr)
+            .await;
+        self.check(res)
+    }
+
+    pub async fn broadcast(&mut self, cmd: &str) -> Result<(), Error> {
+        let res = self
+            .conn
+            .as_mut()
+            .ok_or(Error::Disconnected)?
+            .broadcast(cmd)
+            .await;
+        self.check(res)
+    }
+
+    pub async fn request(&mut self, cmd: &str) -> Result<String, Error> {
+        let res = self
+            .conn
+            .as_mut()
+            .ok_or(Error::Disconnected)?
+            .request(cmd)
+            .await;
+        self.check(res)
+    }
+}
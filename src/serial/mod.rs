@@ -1,18 +1,40 @@
+pub mod checksum;
+mod code;
 mod conn;
+mod health;
+mod link;
+mod protocol;
+mod sched;
+mod stats;
+pub use code::*;
 use conn::*;
+use health::Health;
+pub use link::Transport;
+use link::*;
+pub use protocol::{Genesys, Protocol, Scpi, Zup};
+use sched::Queue;
+pub use stats::Stats;
 
-use request_channel::{channel as request_channel, Requester, Responder};
 use std::{
     collections::{hash_map::Entry, HashMap},
-    io,
+    future::Future,
+    io, mem,
+    pin::Pin,
     string::FromUtf8Error,
-    sync::Arc,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
 };
 use thiserror::Error;
 use tokio::{
-    io::{split, AsyncRead, AsyncWrite},
     runtime, select,
-    sync::{mpsc::unbounded_channel as channel, Notify},
+    sync::{
+        mpsc::{
+            unbounded_channel as channel, UnboundedReceiver as Receiver, UnboundedSender as Sender,
+        },
+        oneshot, watch, Notify,
+    },
+    time::{sleep_until, Instant},
 };
 
 pub type Addr = u8;
@@ -21,6 +43,9 @@ pub type CmdRes = String;
 
 pub const LINE_TERM: u8 = b'\r';
 
+/// Number of attempts to open connection while discovering devices.
+const CONNECT_ATTEMPTS: usize = 3;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("I/O Error: {0}")]
@@ -29,183 +54,728 @@ pub enum Error {
     Decode(#[from] FromUtf8Error),
     #[error("Timeout")]
     Timeout,
+    #[error("Not connected")]
+    Disconnected,
+    #[error("Checksum mismatch")]
+    Checksum,
+    /// Echo of transmitted bytes differs from them.
+    #[error("Bus collision")]
+    Collision(Vec<u8>),
+    #[error("Response doesn't match command: {0}")]
+    Mismatch(String),
     #[error("Unexpected response from device: {0}")]
-    Device(String),
+    Unexpected(String),
+    #[error("Device error: {0}")]
+    Device(#[from] DeviceError),
+    #[error("SCPI error {0}")]
+    Scpi(#[from] ScpiError),
+    #[error("Cannot select device {0}: {1}")]
+    Select(Addr, Box<Error>),
+    #[error("Device is offline")]
+    Offline,
+    #[error("Device is removed")]
+    Removed,
+    #[error("Command wasn't executed")]
+    Dropped,
+    #[error("Request expired before execution")]
+    Expired,
+    #[error("Command was superseded by newer one")]
+    Superseded,
+}
+
+#[derive(Debug, Clone)]
+pub struct Timing {
+    /// Delay before sending each command.
+    pub delay: Duration,
+    /// Time to wait for response.
+    pub timeout: Duration,
+    /// Number of attempts to send command.
+    pub retries: usize,
+    /// Default time for request to wait for execution before it's dropped.
+    pub expiry: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Framing {
+    /// Append checksum to commands and verify it in responses, used by [`Genesys`] protocol.
+    pub checksum: bool,
+    /// Transmitted bytes are echoed back and must be read before response.
+    pub echo: bool,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    /// Number of immediate commands executed in a row while queued ones are waiting.
+    pub immediate_burst: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Priority {
-    Queued,
+    /// Executed in order of deadlines after immediate commands.
+    Queued(Instant),
+    /// Executed before any queued command.
     Immediate,
 }
 
-#[derive(Debug, Clone)]
-struct ImmTx {
+impl Priority {
+    /// Queued command which should be executed as soon as possible.
+    pub fn queued() -> Self {
+        Self::Queued(Instant::now())
+    }
+}
+
+type Rx = Result<CmdRes, Error>;
+
+struct Request {
     addr: Addr,
     cmd: Cmd,
+    /// Pending immediate request with the same key to the same device is replaced by this one.
+    key: Option<Cmd>,
+    created: Instant,
+    /// Request is dropped if it isn't executed until this time.
+    expires: Instant,
+    resp: oneshot::Sender<Rx>,
 }
-#[derive(Debug, Clone)]
-enum QueTx {
-    Cmd(Cmd),
-    Yield,
+
+struct Broadcast {
+    cmd: Cmd,
+    resp: oneshot::Sender<()>,
+}
+
+enum Job {
+    Request(Request),
+    Broadcast(Broadcast),
+    /// Check whether offline device responds.
+    Probe(Addr),
 }
-type Rx = CmdRes;
 
 pub struct Handle {
     pub req: Commander,
     pub intr: Interrupt,
+    pub online: Online,
+    /// Notified after broadcast command which could change device settings.
+    pub changed: Arc<Notify>,
 }
 
+#[derive(Clone)]
 pub struct Commander {
     addr: Addr,
-    imm: Arc<Requester<ImmTx, Rx>>,
-    que: Requester<QueTx, Rx>,
+    /// Time for request to wait for execution.
+    expiry: Duration,
+    imm: Sender<Request>,
+    que: Sender<(Instant, Request)>,
 }
 
 impl Commander {
-    pub async fn execute(&self, cmd: Cmd, priority: Priority) -> Option<CmdRes> {
+    /// Commander which requests expire after given time.
+    pub fn with_expiry(&self, expiry: Duration) -> Self {
+        Self {
+            expiry,
+            ..self.clone()
+        }
+    }
+
+    /// Execute command and wait for response.
+    ///
+    /// Fails with [`Error::Expired`] if command isn't started until expiry time.
+    pub async fn execute(&self, cmd: Cmd, priority: Priority) -> Result<CmdRes, Error> {
+        self.submit(cmd, None, priority)?.await
+    }
+
+    /// Queue command which makes pending immediate command with the same key obsolete, without waiting for it.
+    ///
+    /// The pending command isn't sent and its reply is [`Error::Superseded`],
+    /// so only the newest value is written when parameter is changed frequently.
+    pub fn submit_keyed(&self, cmd: Cmd, key: &str, priority: Priority) -> Result<Reply, Error> {
+        self.submit(cmd, Some(String::from(key)), priority)
+    }
+
+    fn submit(&self, cmd: Cmd, key: Option<Cmd>, priority: Priority) -> Result<Reply, Error> {
+        let (resp, rx) = oneshot::channel();
+        let now = Instant::now();
+        let req = Request {
+            addr: self.addr,
+            cmd,
+            key,
+            created: now,
+            expires: now + self.expiry,
+            resp,
+        };
         match priority {
-            Priority::Immediate => self
-                .imm
-                .request(ImmTx {
-                    addr: self.addr,
-                    cmd,
-                })
-                .unwrap(),
-            Priority::Queued => self.que.request(QueTx::Cmd(cmd)).unwrap(),
+            Priority::Immediate => self.imm.send(req).map_err(|_| Error::Dropped)?,
+            Priority::Queued(deadline) => {
+                self.que.send((deadline, req)).map_err(|_| Error::Dropped)?
+            }
         }
-        .get_response()
-        .await
+        Ok(Reply(rx))
     }
-    pub fn yield_(&self) {
-        // Don't wait for response.
-        let _ = self.que.request(QueTx::Yield).unwrap();
+}
+
+/// Response to submitted command, ready when command is executed or dropped.
+pub struct Reply(oneshot::Receiver<Rx>);
+
+impl Future for Reply {
+    type Output = Result<CmdRes, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|res| res.unwrap_or(Err(Error::Dropped)))
+    }
+}
+
+/// Bus-level handle to send commands to all devices at once.
+#[derive(Clone)]
+pub struct Broadcaster {
+    req: Sender<Broadcast>,
+}
+
+impl Broadcaster {
+    /// Send command without address to all devices and wait until it's transmitted.
+    ///
+    /// Returns `false` if command wasn't sent.
+    pub async fn broadcast(&self, cmd: Cmd) -> bool {
+        let (resp, rx) = oneshot::channel();
+        self.req.send(Broadcast { cmd, resp }).is_ok() && rx.await.is_ok()
     }
 }
 
 pub type Interrupt = Arc<Notify>;
 
+/// Whether device can be communicated with.
+pub type Online = watch::Receiver<bool>;
+
 struct Client {
-    resp: Responder<QueTx, Rx>,
     intr: Interrupt,
+    changed: Arc<Notify>,
+    online: watch::Sender<bool>,
+    health: Health,
 }
 
-pub struct Multiplexer<Port: AsyncRead + AsyncWrite + Unpin> {
-    port: Port,
-    clients: HashMap<Addr, Client>,
-    imm: Responder<ImmTx, Rx>,
-    imm_req: Arc<Requester<ImmTx, Rx>>,
+impl Client {
+    fn update_online(&self, connected: bool) {
+        let value = connected && self.health.is_online();
+        self.online
+            .send_if_modified(|state| mem::replace(state, value) != value);
+    }
 }
 
-impl<Port: AsyncRead + AsyncWrite + Unpin> Multiplexer<Port> {
-    pub fn new(port: Port) -> Self {
-        let (req, resp) = request_channel::<ImmTx, Rx>();
-        Self {
-            port,
-            imm: resp,
-            imm_req: Arc::new(req),
-            clients: HashMap::new(),
+#[derive(Default)]
+struct Clients {
+    map: HashMap<Addr, Client>,
+    /// Whether bus is connected.
+    connected: bool,
+}
+
+impl Clients {
+    fn set_connected(&mut self, value: bool) {
+        self.connected = value;
+        for client in self.map.values() {
+            client.update_online(value);
+        }
+    }
+
+    fn is_online(&self, addr: Addr) -> bool {
+        self.map
+            .get(&addr)
+            .map(|client| client.health.is_online())
+            .unwrap_or(false)
+    }
+
+    /// Record whether device responded, returns new online state if it has changed.
+    fn report(&mut self, addr: Addr, responded: bool) -> Option<bool> {
+        let client = self.map.get_mut(&addr)?;
+        if !client.health.report(responded) {
+            return None;
         }
+        client.update_online(self.connected);
+        Some(client.health.is_online())
+    }
+
+    /// Offline device to probe next and time to do it.
+    fn next_probe(&self) -> Option<(Addr, Instant)> {
+        self.map
+            .iter()
+            .filter_map(|(addr, client)| Some((*addr, client.health.next_probe()?)))
+            .min_by_key(|(_, time)| *time)
     }
+}
 
-    pub fn add_client(&mut self, addr: Addr) -> Option<Handle> {
-        let vacant = match self.clients.entry(addr) {
+/// Handle to add and remove devices, can be used while multiplexer is running.
+#[derive(Clone)]
+pub struct Control {
+    clients: Arc<Mutex<Clients>>,
+    expiry: Duration,
+    imm_req: Sender<Request>,
+    que_req: Sender<(Instant, Request)>,
+}
+
+impl Control {
+    /// Add device with given address, returns `None` if it's already present.
+    pub fn add_client(&self, addr: Addr) -> Option<Handle> {
+        let mut clients = self.clients.lock().unwrap();
+        let online = clients.connected;
+        let vacant = match clients.map.entry(addr) {
             Entry::Vacant(vacant) => vacant,
             Entry::Occupied(..) => return None,
         };
-        let (req, resp) = request_channel::<QueTx, Rx>();
         let intr = Arc::new(Notify::new());
+        let changed = Arc::new(Notify::new());
+        let (online_sender, online) = watch::channel(online);
         vacant.insert(Client {
-            resp,
             intr: intr.clone(),
+            changed: changed.clone(),
+            online: online_sender,
+            health: Health::default(),
         });
         Some(Handle {
             req: Commander {
                 addr,
+                expiry: self.expiry,
                 imm: self.imm_req.clone(),
-                que: req,
+                que: self.que_req.clone(),
             },
             intr,
+            online,
+            changed,
         })
     }
 
-    pub async fn run(mut self) -> ! {
-        let (mut clients, client_intrs): (HashMap<_, _>, HashMap<_, _>) = {
-            self.clients
-                .into_iter()
-                .map(|(addr, client)| ((addr, client.resp), (addr, client.intr)))
-                .unzip()
+    /// Remove device, returns `false` if there is no such device.
+    ///
+    /// Pending requests of the device are dropped and its [`Online`] receiver is closed.
+    pub fn remove_client(&self, addr: Addr) -> bool {
+        self.clients.lock().unwrap().map.remove(&addr).is_some()
+    }
+
+    fn contains(&self, addr: Addr) -> bool {
+        self.clients.lock().unwrap().map.contains_key(&addr)
+    }
+
+    fn is_online(&self, addr: Addr) -> bool {
+        self.clients.lock().unwrap().is_online(addr)
+    }
+
+    fn report(&self, addr: Addr, responded: bool) -> Option<bool> {
+        self.clients.lock().unwrap().report(addr, responded)
+    }
+
+    fn next_probe(&self) -> Option<(Addr, Instant)> {
+        self.clients.lock().unwrap().next_probe()
+    }
+}
+
+/// Information about device found on the bus.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub addr: Addr,
+    /// Response to identity query (`IDN?`).
+    pub ident: String,
+    /// Response to `SN?`, empty if not supported.
+    pub serial: String,
+    /// Response to `REV?`, empty if not supported.
+    pub revision: String,
+}
+
+/// Whether device responded, even with error, `None` if connection is lost and it's unknown.
+///
+/// Connection loss is a fault of bus, not of device, so it doesn't affect device health.
+fn responded<R>(res: &Result<R, Error>) -> Option<bool> {
+    match res {
+        Err(Error::Disconnected | Error::Io(..)) => None,
+        Err(Error::Timeout) => Some(false),
+        _ => Some(true),
+    }
+}
+
+pub struct Multiplexer<T: Transport> {
+    name: String,
+    link: Link<T>,
+    protocol: Arc<dyn Protocol>,
+    intr: Receiver<Addr>,
+    control: Control,
+    imm: Receiver<Request>,
+    que: Receiver<(Instant, Request)>,
+    bcast: Receiver<Broadcast>,
+    bcast_req: Sender<Broadcast>,
+    schedule: Schedule,
+    stats: Arc<Stats>,
+}
+
+impl<T: Transport> Multiplexer<T> {
+    pub fn new(
+        name: &str,
+        transport: T,
+        protocol: Arc<dyn Protocol>,
+        timing: Timing,
+        framing: Framing,
+        schedule: Schedule,
+    ) -> Self {
+        let (imm_req, imm) = channel();
+        let (que_req, que) = channel();
+        let (bcast_req, bcast) = channel();
+        let (intr_sender, intr) = channel::<Addr>();
+        let stats = Arc::new(Stats::default());
+        let expiry = timing.expiry;
+        Self {
+            name: String::from(name),
+            link: Link::new(
+                name,
+                transport,
+                protocol.clone(),
+                timing,
+                framing,
+                intr_sender,
+                stats.clone(),
+            ),
+            protocol,
+            intr,
+            control: Control {
+                clients: Arc::default(),
+                expiry,
+                imm_req,
+                que_req,
+            },
+            imm,
+            que,
+            bcast,
+            bcast_req,
+            schedule,
+            stats,
+        }
+    }
+
+    /// Handle to send commands to all devices.
+    pub fn broadcaster(&self) -> Broadcaster {
+        Broadcaster {
+            req: self.bcast_req.clone(),
+        }
+    }
+
+    /// Communication counters, updated while multiplexer is running.
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Handle to add and remove devices.
+    pub fn control(&self) -> Control {
+        self.control.clone()
+    }
+
+    /// Sweep given addresses and collect information about devices that respond.
+    ///
+    /// Must be called before [`Self::run`].
+    /// Fails with [`Error::Disconnected`] if connection cannot be opened in [`CONNECT_ATTEMPTS`].
+    pub async fn discover<I: IntoIterator<Item = Addr>>(
+        &mut self,
+        addrs: I,
+    ) -> Result<Vec<DeviceInfo>, Error> {
+        let mut found = Vec::new();
+        for addr in addrs {
+            if !self.link.is_connected() && !self.link.try_connect(CONNECT_ATTEMPTS).await {
+                return Err(Error::Disconnected);
+            }
+            match self.link.select(addr).await {
+                Ok(..) => (),
+                Err(Error::Timeout) => continue,
+                Err(err) => {
+                    log::warn!("{}: Cannot probe address {}: {}", self.name, addr, err);
+                    continue;
+                }
+            }
+            match self.identify(addr).await {
+                Ok(info) => {
+                    log::info!(
+                        "{}: Found device {}: '{}', SN: '{}', REV: '{}'",
+                        self.name,
+                        addr,
+                        info.ident,
+                        info.serial,
+                        info.revision
+                    );
+                    found.push(info);
+                }
+                Err(err) => {
+                    log::warn!("{}: Cannot identify device {}: {}", self.name, addr, err);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Query device identity, details which device fails to report are left empty.
+    async fn identify(&mut self, addr: Addr) -> Result<DeviceInfo, Error> {
+        let (serial, revision) = self.protocol.details();
+        let ident = self.query(Some(self.protocol.ident())).await?;
+        Ok(DeviceInfo {
+            addr,
+            ident,
+            serial: self.detail(addr, serial).await,
+            revision: self.detail(addr, revision).await,
+        })
+    }
+
+    /// Response to optional detail query, empty if device doesn't answer it.
+    async fn detail(&mut self, addr: Addr, cmd: Option<Cmd>) -> String {
+        let res = self.query(cmd.clone()).await;
+        res.unwrap_or_else(|err| {
+            log::debug!(
+                "{}: Device {} doesn't answer '{}': {}",
+                self.name,
+                addr,
+                cmd.unwrap_or_default(),
+                err
+            );
+            String::new()
+        })
+    }
+
+    /// Response to query, empty if there is no query.
+    async fn query(&mut self, cmd: Option<Cmd>) -> Result<String, Error> {
+        match cmd {
+            Some(cmd) => self.link.request(&cmd).await,
+            None => Ok(String::new()),
+        }
+    }
+
+    pub async fn run(self) -> ! {
+        let Self {
+            name,
+            mut link,
+            protocol,
+            mut intr,
+            control,
+            mut imm,
+            mut que,
+            mut bcast,
+            schedule,
+            stats,
+            ..
+        } = self;
+        let set_connected = |value: bool| control.clients.lock().unwrap().set_connected(value);
+        let report = |addr: Addr, responded: Option<bool>| {
+            if let Some(online) = responded.and_then(|responded| control.report(addr, responded)) {
+                if online {
+                    log::info!("{}: Device {} is online", name, addr);
+                } else {
+                    log::warn!("{}: Device {} is offline", name, addr);
+                }
+            }
         };
 
-        let (intr_sender, mut intr) = channel::<Addr>();
+        let intr_name = name.clone();
+        let intr_clients = control.clients.clone();
+        let intr_stats = stats.clone();
         runtime::Handle::current().spawn(async move {
-            let clients = client_intrs;
             loop {
                 let addr = intr.recv().await.unwrap();
-                log::trace!("Intr: {}", addr);
-                clients[&addr].notify_one();
+                log::trace!("{}: Intr: {}", intr_name, addr);
+                match intr_clients.lock().unwrap().map.get(&addr) {
+                    Some(client) => client.intr.notify_one(),
+                    None => {
+                        log::warn!("{}: Interrupt from unknown device {}", intr_name, addr);
+                        stats::inc(&intr_stats.unknown_srqs);
+                    }
+                }
             }
         });
 
-        let mut conn = Connection::new(split(self.port), intr_sender);
-
-        let mut sched = {
-            let mut addrs = clients.keys().copied().collect::<Vec<_>>();
-            addrs.sort();
-            addrs.into_iter().cycle()
-        };
-        let mut current = sched.next().unwrap();
-        let mut active = None;
+        let mut queue = Queue::new(schedule, stats);
         loop {
-            let (addr, cmd, r) = select! {
-                // Read immediate commands from all clients
-                imm = self.imm.next() => {
-                    let (ImmTx { addr, cmd }, r) = imm.unwrap();
-                    (addr, cmd, r)
-                },
-                // Read queued commands from current client
-                que = clients.get_mut(&current.clone()).unwrap().next() => {
-                    match que {
-                        Some((QueTx::Cmd(cmd), r)) => (current, cmd, r),
-                        None | Some((QueTx::Yield, ..)) => {
-                            current = sched.next().unwrap();
-                            continue;
-                        },
+            // Reconnect if connection is lost
+            if !link.is_connected() {
+                set_connected(false);
+                link.connect().await;
+            }
+            set_connected(true);
+
+            // Take broadcast command if any, then probe offline device if it's time,
+            // otherwise take next command grouping them by address
+            let job = loop {
+                if let Ok(req) = bcast.try_recv() {
+                    break Job::Broadcast(req);
+                }
+                let probe = control.next_probe();
+                if let Some((addr, time)) = probe {
+                    if time <= Instant::now() {
+                        break Job::Probe(addr);
                     }
-                },
+                }
+                while let Ok(req) = imm.try_recv() {
+                    queue.push_immediate(req);
+                }
+                while let Ok((deadline, req)) = que.try_recv() {
+                    queue.push_queued(deadline, req);
+                }
+                if let Some(req) = queue.pop() {
+                    break Job::Request(req);
+                }
+                select! {
+                    Some(req) = bcast.recv() => break Job::Broadcast(req),
+                    req = imm.recv() => queue.push_immediate(req.unwrap()),
+                    req = que.recv() => {
+                        let (deadline, req) = req.unwrap();
+                        queue.push_queued(deadline, req);
+                    }
+                    () = sleep_until(probe.map(|(_, time)| time).unwrap_or_else(Instant::now)),
+                        if probe.is_some() => (),
+                }
             };
-
-            // Switch active address if needed
-            if !active.map(|a| addr == a).unwrap_or(false) {
-                match conn
-                    .request(&format!("ADR {}", addr))
-                    .await
-                    .and_then(|resp| {
-                        if resp == "OK" {
-                            Ok(())
-                        } else {
-                            Err(Error::Device(resp))
+            let Request {
+                addr,
+                cmd,
+                expires,
+                resp,
+                ..
+            } = match job {
+                Job::Request(req) => req,
+                Job::Broadcast(Broadcast { cmd, resp }) => {
+                    if !protocol.has_broadcast() {
+                        log::error!("{}: Broadcast isn't supported by protocol", name);
+                        continue;
+                    }
+                    log::info!("{}: Broadcast '{}'", name, cmd);
+                    match link.broadcast(&cmd).await {
+                        Ok(()) => {
+                            let _ = resp.send(());
+                            for client in control.clients.lock().unwrap().map.values() {
+                                client.changed.notify_one();
+                            }
+                        }
+                        Err(err) => {
+                            log::error!("{}: Cannot broadcast '{}': {}", name, cmd, err);
                         }
-                    }) {
-                    Ok(()) => {
-                        active.replace(addr);
                     }
-                    Err(err) => {
-                        log::error!("Cannot set device address {}: {}", addr, err);
-                        continue;
+                    continue;
+                }
+                Job::Probe(addr) => {
+                    log::debug!("{}: Probe device {}", name, addr);
+                    let mut res = link.select(addr).await.map(|_| ());
+                    if res.is_ok() {
+                        res = link.request(&protocol.ident()).await.map(|_| ());
                     }
+                    report(addr, responded(&res));
+                    continue;
                 }
+            };
+            // Requester isn't waiting for response anymore
+            if resp.is_closed() {
+                continue;
+            }
+            if expires <= Instant::now() {
+                log::debug!("{}: Drop expired '{}' to device {}", name, cmd, addr);
+                let _ = resp.send(Err(Error::Expired));
+                continue;
+            }
+            // Device has been removed or is offline
+            if !control.contains(addr) {
+                log::debug!("{}: Drop '{}' to removed device {}", name, cmd, addr);
+                let _ = resp.send(Err(Error::Removed));
+                continue;
+            }
+            if !control.is_online(addr) {
+                log::debug!("{}: Drop '{}' to offline device {}", name, cmd, addr);
+                let _ = resp.send(Err(Error::Offline));
+                continue;
             }
 
-            // Execute command
-            match conn.request(&cmd).await {
-                Ok(resp) => {
-                    r.respond(resp);
-                }
-                Err(err) => {
-                    log::error!("Cannot run command '{}': {}", cmd, err);
-                }
+            // Switch active address if needed, already selected device is judged by command only
+            let res = link.select(addr).await;
+            if !matches!(res, Ok(false)) {
+                report(addr, responded(&res));
+            }
+            if let Err(err) = res {
+                let _ = resp.send(Err(Error::Select(addr, Box::new(err))));
+                continue;
             }
+
+            // Execute command, errors are passed to requester
+            let res = link.request(&cmd).await;
+            report(addr, responded(&res));
+            let _ = resp.send(res);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+    use tokio::io::{duplex, AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+    /// Transport opening single in-memory port.
+    struct Pipe(Mutex<Option<DuplexStream>>);
+
+    impl Transport for Pipe {
+        type Port = DuplexStream;
+
+        fn open(&self) -> BoxFuture<'_, io::Result<DuplexStream>> {
+            let port = self.0.lock().unwrap().take();
+            Box::pin(async { port.ok_or_else(|| io::ErrorKind::NotConnected.into()) })
+        }
+    }
+
+    /// Genesys device answering `OK` to every command, returns commands it received.
+    fn device(port: DuplexStream) -> Arc<Mutex<Vec<String>>> {
+        let cmds = Arc::new(Mutex::new(Vec::new()));
+        let log = cmds.clone();
+        runtime::Handle::current().spawn(async move {
+            let (reader, mut writer) = tokio::io::split(port);
+            let mut reader = BufReader::new(reader);
+            let mut buf = Vec::new();
+            while reader.read_until(LINE_TERM, &mut buf).await.unwrap() > 0 {
+                buf.pop();
+                log.lock()
+                    .unwrap()
+                    .push(String::from_utf8(buf.split_off(0)).unwrap());
+                writer.write_all(b"OK\r").await.unwrap();
+            }
+        });
+        cmds
+    }
+
+    fn multiplexer(port: DuplexStream) -> Multiplexer<Pipe> {
+        Multiplexer::new(
+            "test",
+            Pipe(Mutex::new(Some(port))),
+            Arc::new(Genesys::default()),
+            Timing {
+                delay: Duration::ZERO,
+                timeout: Duration::from_millis(100),
+                retries: 1,
+                expiry: Duration::from_secs(1),
+            },
+            Framing::default(),
+            Schedule { immediate_burst: 4 },
+        )
+    }
+
+    #[test]
+    fn connection_loss_isnt_reported() {
+        assert_eq!(responded(&Ok(())), Some(true));
+        assert_eq!(
+            responded::<()>(&Err(Error::Device(DeviceError::Checksum))),
+            Some(true)
+        );
+        assert_eq!(responded::<()>(&Err(Error::Timeout)), Some(false));
+        assert_eq!(responded::<()>(&Err(Error::Disconnected)), None);
+        let err = io::Error::from(io::ErrorKind::BrokenPipe);
+        assert_eq!(responded::<()>(&Err(Error::Io(err))), None);
+    }
+
+    #[tokio::test]
+    async fn keyed_writes_coalesce() {
+        let (port, dev_port) = duplex(256);
+        let cmds = device(dev_port);
+        let mux = multiplexer(port);
+        let cmdr = mux.control().add_client(1).unwrap().req;
+        runtime::Handle::current().spawn(mux.run());
+
+        let key = "PV";
+        let first = cmdr.submit_keyed(String::from("PV 1"), key, Priority::Immediate);
+        let second = cmdr.submit_keyed(String::from("PV 2"), key, Priority::Immediate);
+        assert!(matches!(first.unwrap().await, Err(Error::Superseded)));
+        assert_eq!(second.unwrap().await.unwrap(), "OK");
+        assert_eq!(*cmds.lock().unwrap(), ["ADR 1", "PV 2"]);
+    }
+}
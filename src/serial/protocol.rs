@@ -0,0 +1,310 @@
+use super::{checksum, Addr, Cmd, DeviceError, Error, ScpiError, LINE_TERM};
+
+/// Bus protocol: how devices are addressed and how messages are framed.
+pub trait Protocol: Send + Sync + 'static {
+    /// Byte terminating each response.
+    fn terminator(&self) -> u8;
+
+    /// Command to select device with given address.
+    fn select(&self, addr: Addr) -> Cmd;
+
+    /// Query of device identity, also used to check whether device responds.
+    fn ident(&self) -> Cmd;
+
+    /// Queries of serial number and firmware revision, if device supports them.
+    fn details(&self) -> (Option<Cmd>, Option<Cmd>);
+
+    /// Whether device sends response to command.
+    fn has_response(&self, cmd: &str) -> bool;
+
+    /// Whether commands can be sent to all devices at once.
+    fn has_broadcast(&self) -> bool;
+
+    /// Whether response means that command is accepted.
+    ///
+    /// Response to command without one is empty.
+    fn is_ack(&self, resp: &str) -> bool;
+
+    /// Message to transmit command, including terminator.
+    fn encode(&self, cmd: &str) -> String;
+
+    /// Remove framing from response without terminator, returns `None` if it's broken.
+    fn decode<'a>(&self, resp: &'a str) -> Option<&'a str>;
+
+    /// Error code if response is it.
+    fn error(&self, resp: &str) -> Option<DeviceError>;
+
+    /// Query reading error of the last command from device error queue, if device has one.
+    ///
+    /// It's sent after each command without response.
+    fn error_query(&self) -> Option<Cmd>;
+
+    /// Check response to [`Self::error_query`].
+    fn queued_error(&self, resp: &str) -> Result<(), Error>;
+
+    /// Whether response can belong to command.
+    fn is_expected(&self, cmd: &str, resp: &str) -> bool;
+
+    /// Address of device which requested service, if byte isn't a part of message.
+    ///
+    /// Service request consists of two equal bytes.
+    fn interrupt(&self, byte: u8) -> Option<Addr>;
+}
+
+/// TDK-Lambda Genesys protocol.
+#[derive(Debug, Clone, Default)]
+pub struct Genesys {
+    /// Append checksum to commands and verify it in responses.
+    pub checksum: bool,
+}
+
+impl Protocol for Genesys {
+    fn terminator(&self) -> u8 {
+        LINE_TERM
+    }
+
+    fn select(&self, addr: Addr) -> Cmd {
+        format!("ADR {}", addr)
+    }
+
+    fn ident(&self) -> Cmd {
+        String::from("IDN?")
+    }
+
+    fn details(&self) -> (Option<Cmd>, Option<Cmd>) {
+        (Some(String::from("SN?")), Some(String::from("REV?")))
+    }
+
+    fn has_response(&self, _cmd: &str) -> bool {
+        true
+    }
+
+    fn has_broadcast(&self) -> bool {
+        true
+    }
+
+    fn is_ack(&self, resp: &str) -> bool {
+        resp == "OK"
+    }
+
+    fn encode(&self, cmd: &str) -> String {
+        let mut msg = if self.checksum {
+            checksum::append(cmd)
+        } else {
+            String::from(cmd)
+        };
+        msg.push(char::from(LINE_TERM));
+        msg
+    }
+
+    fn decode<'a>(&self, resp: &'a str) -> Option<&'a str> {
+        if self.checksum {
+            checksum::strip(resp)
+        } else {
+            Some(resp)
+        }
+    }
+
+    fn error(&self, resp: &str) -> Option<DeviceError> {
+        DeviceError::parse(resp)
+    }
+
+    fn error_query(&self) -> Option<Cmd> {
+        None
+    }
+
+    fn queued_error(&self, _resp: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Queries return values, other commands return `OK`.
+    fn is_expected(&self, cmd: &str, resp: &str) -> bool {
+        let is_query = cmd.split(' ').next().unwrap_or("").ends_with('?');
+        is_query != self.is_ack(resp)
+    }
+
+    /// SRQ byte is `0x80` plus device address.
+    fn interrupt(&self, byte: u8) -> Option<Addr> {
+        if byte >= 0x80 {
+            Some(byte - 0x80)
+        } else {
+            None
+        }
+    }
+}
+
+/// Lambda ZUP protocol.
+///
+/// Commands look like `:VOL12.500;`, only queries (`:VOL?;`, `:VOL!;`) are answered.
+/// Responses are terminated by `\r\n` and have no error codes.
+#[derive(Debug, Clone, Default)]
+pub struct Zup;
+
+impl Zup {
+    fn is_query(cmd: &str) -> bool {
+        cmd.ends_with("?;") || cmd.ends_with("!;")
+    }
+}
+
+impl Protocol for Zup {
+    fn terminator(&self) -> u8 {
+        b'\n'
+    }
+
+    fn select(&self, addr: Addr) -> Cmd {
+        format!(":ADR{:02};", addr)
+    }
+
+    fn ident(&self) -> Cmd {
+        String::from(":MDL?;")
+    }
+
+    fn details(&self) -> (Option<Cmd>, Option<Cmd>) {
+        (None, Some(String::from(":REV?;")))
+    }
+
+    fn has_response(&self, cmd: &str) -> bool {
+        Self::is_query(cmd)
+    }
+
+    fn has_broadcast(&self) -> bool {
+        false
+    }
+
+    fn is_ack(&self, resp: &str) -> bool {
+        resp.is_empty()
+    }
+
+    fn encode(&self, cmd: &str) -> String {
+        String::from(cmd)
+    }
+
+    fn decode<'a>(&self, resp: &'a str) -> Option<&'a str> {
+        Some(resp.strip_suffix('\r').unwrap_or(resp))
+    }
+
+    fn error(&self, _resp: &str) -> Option<DeviceError> {
+        None
+    }
+
+    fn error_query(&self) -> Option<Cmd> {
+        None
+    }
+
+    fn queued_error(&self, _resp: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn is_expected(&self, _cmd: &str, resp: &str) -> bool {
+        !resp.is_empty()
+    }
+
+    fn interrupt(&self, _byte: u8) -> Option<Addr> {
+        None
+    }
+}
+
+/// SCPI protocol of Genesys+ and Z+ LAN interface.
+///
+/// Only queries are answered, errors are read from error queue by `SYST:ERR?`.
+/// Supplies daisy-chained to the one connected to LAN are selected by `INST:NSEL`.
+#[derive(Debug, Clone, Default)]
+pub struct Scpi;
+
+impl Protocol for Scpi {
+    fn terminator(&self) -> u8 {
+        b'\n'
+    }
+
+    fn select(&self, addr: Addr) -> Cmd {
+        format!("INST:NSEL {}", addr)
+    }
+
+    fn ident(&self) -> Cmd {
+        String::from("*IDN?")
+    }
+
+    /// Serial number and revision are the part of identity.
+    fn details(&self) -> (Option<Cmd>, Option<Cmd>) {
+        (None, None)
+    }
+
+    fn has_response(&self, cmd: &str) -> bool {
+        cmd.split(' ').next().unwrap_or("").ends_with('?')
+    }
+
+    fn has_broadcast(&self) -> bool {
+        false
+    }
+
+    fn is_ack(&self, resp: &str) -> bool {
+        resp.is_empty()
+    }
+
+    fn encode(&self, cmd: &str) -> String {
+        format!("{}\n", cmd)
+    }
+
+    fn decode<'a>(&self, resp: &'a str) -> Option<&'a str> {
+        Some(resp.strip_suffix('\r').unwrap_or(resp))
+    }
+
+    fn error(&self, _resp: &str) -> Option<DeviceError> {
+        None
+    }
+
+    fn error_query(&self) -> Option<Cmd> {
+        Some(String::from("SYST:ERR?"))
+    }
+
+    fn queued_error(&self, resp: &str) -> Result<(), Error> {
+        match ScpiError::parse(resp) {
+            Some(err) if err.code == 0 => Ok(()),
+            Some(err) => Err(Error::Scpi(err)),
+            None => Err(Error::Unexpected(String::from(resp))),
+        }
+    }
+
+    fn is_expected(&self, _cmd: &str, resp: &str) -> bool {
+        !resp.is_empty()
+    }
+
+    fn interrupt(&self, _byte: u8) -> Option<Addr> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesys_expected() {
+        let proto = Genesys::default();
+        assert!(proto.is_expected("PV?", "12.5"));
+        assert!(!proto.is_expected("PV?", "OK"));
+        assert!(proto.is_expected("OUT 1", "OK"));
+        assert!(!proto.is_expected("PV 5", "5.000"));
+    }
+
+    #[test]
+    fn any_response_expected() {
+        assert!(Zup.is_expected(":VOL?;", "SV5.000"));
+        assert!(!Zup.is_expected(":VOL?;", ""));
+        assert!(Scpi.is_expected("VOLT?", "5.000"));
+        assert!(!Scpi.is_expected("VOLT?", ""));
+    }
+
+    #[test]
+    fn scpi_queued_error() {
+        assert!(Scpi.queued_error("0,\"No error\"").is_ok());
+        match Scpi.queued_error("-222,\"Data out of range\"") {
+            Err(Error::Scpi(err)) => assert_eq!(err.code, -222),
+            res => panic!("SCPI error expected, got {:?}", res),
+        }
+        assert!(matches!(
+            Scpi.queued_error("garbage"),
+            Err(Error::Unexpected(_))
+        ));
+    }
+}
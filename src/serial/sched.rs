@@ -0,0 +1,397 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, VecDeque},
+    mem,
+    sync::{atomic, Arc},
+};
+use tokio::time::Instant;
+
+use super::{
+    stats::{self, Stats},
+    Addr, Error, Request, Schedule,
+};
+
+/// Maximal number of commands to the same address in a row while other addresses are waiting.
+const MAX_BATCH: usize = 8;
+
+/// Queued request ordered by deadline.
+struct Entry {
+    deadline: Instant,
+    /// Sequence number to keep order of requests with the same deadline.
+    seq: u64,
+    req: Request,
+}
+
+impl Entry {
+    fn key(&self) -> (Instant, u64) {
+        (self.deadline, self.seq)
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Entry {
+    /// Reversed to pop the earliest deadline first from max-heap.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+/// Class of request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Class {
+    Immediate,
+    Queued,
+}
+
+/// Pending requests grouped by address.
+///
+/// Immediate requests are executed before queued ones, but no more than [`Schedule::immediate_burst`] in a row
+/// while queued ones are waiting. Keyed immediate request replaces pending one with the same key in its place.
+/// Queued requests are executed in order of their deadlines.
+/// Requests to the address of the previous one are preferred to reduce address switching
+/// unless [`MAX_BATCH`] is reached or the earliest deadline has passed.
+pub struct Queue {
+    schedule: Schedule,
+    stats: Arc<Stats>,
+    imm: HashMap<Addr, VecDeque<(u64, Request)>>,
+    que: HashMap<Addr, BinaryHeap<Entry>>,
+    seq: u64,
+    /// Number of pending requests of each class.
+    len: (usize, usize),
+    /// Address of previous request and number of requests to it in a row.
+    batch: Option<(Addr, usize)>,
+    /// Number of immediate requests in a row taken while queued ones are waiting.
+    burst: usize,
+}
+
+impl Queue {
+    pub fn new(schedule: Schedule, stats: Arc<Stats>) -> Self {
+        Self {
+            schedule,
+            stats,
+            imm: HashMap::new(),
+            que: HashMap::new(),
+            seq: 0,
+            len: (0, 0),
+            batch: None,
+            burst: 0,
+        }
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn update_stats(&self) {
+        let (imm, que) = self.len;
+        self.stats
+            .imm_pending
+            .store(imm as u64, atomic::Ordering::Relaxed);
+        self.stats
+            .que_pending
+            .store(que as u64, atomic::Ordering::Relaxed);
+    }
+
+    pub fn push_immediate(&mut self, req: Request) {
+        let seq = self.next_seq();
+        let reqs = self.imm.entry(req.addr).or_default();
+        if let Some(key) = &req.key {
+            if let Some((_, pending)) = reqs
+                .iter_mut()
+                .find(|(_, pending)| pending.key.as_ref() == Some(key))
+            {
+                let old = mem::replace(pending, req);
+                let _ = old.resp.send(Err(Error::Superseded));
+                return;
+            }
+        }
+        reqs.push_back((seq, req));
+        self.len.0 += 1;
+        self.update_stats();
+    }
+
+    pub fn push_queued(&mut self, deadline: Instant, req: Request) {
+        let seq = self.next_seq();
+        self.que
+            .entry(req.addr)
+            .or_default()
+            .push(Entry { deadline, seq, req });
+        self.len.1 += 1;
+        self.update_stats();
+    }
+
+    /// Address and class to take next request for.
+    fn next(&self) -> Option<(Addr, Class)> {
+        let current = self
+            .batch
+            .filter(|(_, count)| *count < MAX_BATCH)
+            .map(|(addr, _)| addr);
+
+        let oldest_imm = self
+            .imm
+            .iter()
+            .filter_map(|(addr, reqs)| Some((*addr, reqs.front()?.0)))
+            .min_by_key(|(_, seq)| *seq);
+        if let Some((addr, _)) = oldest_imm {
+            if self.que.is_empty() || self.burst < self.schedule.immediate_burst {
+                let addr = match current {
+                    Some(current) if self.imm.contains_key(&current) => current,
+                    _ => addr,
+                };
+                return Some((addr, Class::Immediate));
+            }
+        }
+
+        let (addr, deadline) = self
+            .que
+            .iter()
+            .filter_map(|(addr, reqs)| Some((*addr, reqs.peek()?.key())))
+            .min_by_key(|(_, key)| *key)?;
+        let addr = match current {
+            Some(current) if self.que.contains_key(&current) && deadline.0 > Instant::now() => {
+                current
+            }
+            _ => addr,
+        };
+        Some((addr, Class::Queued))
+    }
+
+    /// Take next request to execute.
+    pub fn pop(&mut self) -> Option<Request> {
+        let (addr, class) = self.next()?;
+        let req = match class {
+            Class::Immediate => {
+                if !self.que.is_empty() {
+                    self.burst += 1;
+                }
+                let reqs = self.imm.get_mut(&addr).unwrap();
+                let (_, req) = reqs.pop_front().unwrap();
+                if reqs.is_empty() {
+                    self.imm.remove(&addr);
+                }
+                self.len.0 -= 1;
+                stats::max(&self.stats.imm_wait_ms, req.created.elapsed());
+                req
+            }
+            Class::Queued => {
+                self.burst = 0;
+                let reqs = self.que.get_mut(&addr).unwrap();
+                let Entry { req, .. } = reqs.pop().unwrap();
+                if reqs.is_empty() {
+                    self.que.remove(&addr);
+                }
+                self.len.1 -= 1;
+                stats::max(&self.stats.que_wait_ms, req.created.elapsed());
+                req
+            }
+        };
+        self.update_stats();
+        self.batch = Some(match self.batch {
+            Some((prev, count)) if prev == addr => (addr, count + 1),
+            _ => (addr, 1),
+        });
+        Some(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::atomic::AtomicU64, time::Duration};
+    use tokio::sync::oneshot;
+
+    use crate::serial::Rx;
+
+    fn queue(immediate_burst: usize) -> Queue {
+        Queue::new(Schedule { immediate_burst }, Arc::default())
+    }
+
+    fn request(addr: Addr, cmd: &str) -> (Request, oneshot::Receiver<Rx>) {
+        let (resp, rx) = oneshot::channel();
+        let now = Instant::now();
+        let req = Request {
+            addr,
+            cmd: String::from(cmd),
+            key: None,
+            created: now,
+            expires: now + Duration::from_secs(60),
+            resp,
+        };
+        (req, rx)
+    }
+
+    fn push_immediate(queue: &mut Queue, addr: Addr, cmd: &str) {
+        queue.push_immediate(request(addr, cmd).0);
+    }
+
+    fn push_queued(queue: &mut Queue, deadline: Instant, addr: Addr, cmd: &str) {
+        queue.push_queued(deadline, request(addr, cmd).0);
+    }
+
+    /// Take all pending requests, returns their addresses and commands.
+    fn drain(queue: &mut Queue) -> Vec<(Addr, String)> {
+        let mut reqs = Vec::new();
+        while let Some(req) = queue.pop() {
+            reqs.push((req.addr, req.cmd));
+        }
+        reqs
+    }
+
+    fn cmds(reqs: &[(Addr, String)]) -> Vec<&str> {
+        reqs.iter().map(|(_, cmd)| cmd.as_str()).collect()
+    }
+
+    #[test]
+    fn immediate_before_queued() {
+        let mut queue = queue(4);
+        let later = Instant::now() + Duration::from_secs(1);
+        push_queued(&mut queue, later, 1, "MV?");
+        push_queued(&mut queue, Instant::now(), 2, "MC?");
+        push_immediate(&mut queue, 3, "PV 1");
+        assert_eq!(cmds(&drain(&mut queue)), ["PV 1", "MC?", "MV?"]);
+    }
+
+    #[test]
+    fn immediate_burst_limit() {
+        let mut queue = queue(2);
+        push_queued(&mut queue, Instant::now(), 1, "MV?");
+        for cmd in ["PV 1", "PV 2", "PV 3", "PV 4"] {
+            push_immediate(&mut queue, 1, cmd);
+        }
+        assert_eq!(
+            cmds(&drain(&mut queue)),
+            ["PV 1", "PV 2", "MV?", "PV 3", "PV 4"]
+        );
+    }
+
+    #[test]
+    fn keyed_immediate_superseded() {
+        let mut queue = queue(4);
+        let (mut first, mut first_rx) = request(1, "PV 1");
+        first.key = Some(String::from("PV"));
+        let (mut second, _second_rx) = request(1, "PV 2");
+        second.key = Some(String::from("PV"));
+        queue.push_immediate(first);
+        push_immediate(&mut queue, 1, "OUT 1");
+        queue.push_immediate(second);
+        assert!(matches!(first_rx.try_recv(), Ok(Err(Error::Superseded))));
+        assert_eq!(cmds(&drain(&mut queue)), ["PV 2", "OUT 1"]);
+    }
+
+    #[test]
+    fn batch_limit() {
+        let mut queue = queue(4);
+        push_immediate(&mut queue, 1, "PV 0");
+        push_immediate(&mut queue, 2, "PV 0");
+        for i in 1..=MAX_BATCH + 1 {
+            push_immediate(&mut queue, 1, &format!("PV {}", i));
+        }
+        let addrs = drain(&mut queue)
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .collect::<Vec<_>>();
+        let mut expected = vec![1; MAX_BATCH];
+        expected.extend([2, 1, 1]);
+        assert_eq!(addrs, expected);
+    }
+
+    #[test]
+    fn queued_batch_by_address() {
+        let mut queue = queue(4);
+        let now = Instant::now();
+        push_queued(&mut queue, now + Duration::from_secs(10), 1, "MV?");
+        assert_eq!(queue.pop().unwrap().addr, 1);
+        push_queued(&mut queue, now + Duration::from_secs(20), 1, "MC?");
+        push_queued(&mut queue, now + Duration::from_secs(15), 2, "MV?");
+        assert_eq!(
+            drain(&mut queue),
+            [(1, String::from("MC?")), (2, String::from("MV?"))]
+        );
+    }
+
+    #[test]
+    fn overdue_deadline_breaks_batch() {
+        let mut queue = queue(4);
+        let now = Instant::now();
+        push_queued(&mut queue, now + Duration::from_secs(10), 1, "MV?");
+        assert_eq!(queue.pop().unwrap().addr, 1);
+        push_queued(&mut queue, now + Duration::from_secs(20), 1, "MC?");
+        push_queued(&mut queue, now, 2, "MV?");
+        assert_eq!(
+            drain(&mut queue),
+            [(2, String::from("MV?")), (1, String::from("MC?"))]
+        );
+    }
+
+    #[test]
+    fn burst_counted_while_queued_wait() {
+        let mut queue = queue(2);
+        for cmd in ["PV 1", "PV 2", "PV 3"] {
+            push_immediate(&mut queue, 1, cmd);
+        }
+        assert_eq!(cmds(&drain(&mut queue)), ["PV 1", "PV 2", "PV 3"]);
+        push_queued(&mut queue, Instant::now(), 1, "MV?");
+        for cmd in ["PV 4", "PV 5", "PV 6"] {
+            push_immediate(&mut queue, 1, cmd);
+        }
+        assert_eq!(cmds(&drain(&mut queue)), ["PV 4", "PV 5", "MV?", "PV 6"]);
+    }
+
+    fn load(value: &AtomicU64) -> u64 {
+        value.load(atomic::Ordering::Relaxed)
+    }
+
+    #[test]
+    fn pending_counts() {
+        let stats = Arc::new(Stats::default());
+        let mut queue = Queue::new(Schedule { immediate_burst: 4 }, stats.clone());
+        push_immediate(&mut queue, 1, "PV 1");
+        push_immediate(&mut queue, 2, "PV 1");
+        push_queued(&mut queue, Instant::now(), 1, "MV?");
+        assert_eq!((load(&stats.imm_pending), load(&stats.que_pending)), (2, 1));
+
+        let (mut keyed, _rx) = request(1, "PC 1");
+        keyed.key = Some(String::from("PC"));
+        queue.push_immediate(keyed);
+        let (mut keyed, _rx) = request(1, "PC 2");
+        keyed.key = Some(String::from("PC"));
+        queue.push_immediate(keyed);
+        assert_eq!(load(&stats.imm_pending), 3);
+
+        queue.pop();
+        assert_eq!((load(&stats.imm_pending), load(&stats.que_pending)), (2, 1));
+        drain(&mut queue);
+        assert_eq!((load(&stats.imm_pending), load(&stats.que_pending)), (0, 0));
+    }
+
+    #[test]
+    fn wait_maximums() {
+        let stats = Arc::new(Stats::default());
+        let mut queue = Queue::new(Schedule { immediate_burst: 4 }, stats.clone());
+        let (mut old, _rx) = request(1, "PV 1");
+        old.created -= Duration::from_millis(200);
+        queue.push_immediate(old);
+        push_immediate(&mut queue, 1, "PV 2");
+        let (mut old, _rx) = request(1, "MV?");
+        old.created -= Duration::from_millis(100);
+        queue.push_queued(Instant::now(), old);
+        assert_eq!((load(&stats.imm_wait_ms), load(&stats.que_wait_ms)), (0, 0));
+
+        drain(&mut queue);
+        let (imm, que) = (load(&stats.imm_wait_ms), load(&stats.que_wait_ms));
+        assert!((200..1000).contains(&imm), "{}", imm);
+        assert!((100..200).contains(&que), "{}", que);
+    }
+}
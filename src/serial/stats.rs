@@ -0,0 +1,42 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Communication counters of a bus.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Commands sent to devices including retries.
+    pub requests: AtomicU64,
+    /// Selections of another device by `ADR` command.
+    pub adr_switches: AtomicU64,
+    /// Commands left without response.
+    pub timeouts: AtomicU64,
+    /// Input discarded to resynchronize after failed command.
+    pub resyncs: AtomicU64,
+    /// Responses that cannot belong to the command sent.
+    pub mismatches: AtomicU64,
+    /// Responses with bad checksum or commands reported by device to have one.
+    pub checksum_errors: AtomicU64,
+    /// Corrupted echo of transmitted bytes.
+    pub collisions: AtomicU64,
+    /// Service requests from addresses without device.
+    pub unknown_srqs: AtomicU64,
+
+    /// Number of pending immediate commands.
+    pub imm_pending: AtomicU64,
+    /// Number of pending queued commands.
+    pub que_pending: AtomicU64,
+    /// Maximal time immediate command waited for execution in milliseconds, reset on reading.
+    pub imm_wait_ms: AtomicU64,
+    /// Maximal time queued command waited for execution in milliseconds, reset on reading.
+    pub que_wait_ms: AtomicU64,
+}
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn max(value: &AtomicU64, time: Duration) {
+    value.fetch_max(time.as_millis() as u64, Ordering::Relaxed);
+}
@@ -0,0 +1,70 @@
+use futures::future::BoxFuture;
+use std::io;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    runtime,
+};
+use tokio_serial::SerialPortBuilderExt;
+
+use crate::{
+    config::Transport,
+    emulator::Emulator,
+    serial::{self, Addr, Framing},
+};
+
+/// Byte stream to communicate with devices over.
+pub trait Port: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Port for T {}
+
+pub type BoxPort = Box<dyn Port>;
+
+/// Open port of given transport.
+///
+/// For emulator the `framing` and `addrs` of devices to emulate must be provided, emulator task is spawned on current runtime.
+pub async fn open<I: Iterator<Item = Addr>>(
+    transport: &Transport,
+    framing: &Framing,
+    addrs: I,
+) -> io::Result<BoxPort> {
+    Ok(match transport {
+        Transport::Tcp { endpoint } => Box::new(TcpStream::connect(endpoint).await?),
+        Transport::Serial { path, baud_rate } => {
+            Box::new(tokio_serial::new(path, *baud_rate).open_native_async()?)
+        }
+        Transport::Emulator => {
+            let (emu, port) = Emulator::new(framing.clone(), addrs);
+            runtime::Handle::current().spawn(emu.run());
+            Box::new(port)
+        }
+    })
+}
+
+/// Opens ports of configured transport, used by multiplexer to reconnect.
+pub struct Factory {
+    transport: Transport,
+    framing: Framing,
+    addrs: Vec<Addr>,
+}
+
+impl Factory {
+    pub fn new<I: Iterator<Item = Addr>>(transport: Transport, framing: Framing, addrs: I) -> Self {
+        Self {
+            transport,
+            framing,
+            addrs: addrs.collect(),
+        }
+    }
+}
+
+impl serial::Transport for Factory {
+    type Port = BoxPort;
+
+    fn open(&self) -> BoxFuture<'_, io::Result<BoxPort>> {
+        Box::pin(open(
+            &self.transport,
+            &self.framing,
+            self.addrs.iter().copied(),
+        ))
+    }
+}